@@ -0,0 +1,435 @@
+use data::data_lines;
+use entities::{poisson, RandomRangeGen, Segment, SimRng};
+
+use rand::Rng;
+
+use std::f32::consts::PI;
+
+use std::fmt;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// A user entering the simulation
+#[derive(Debug, PartialEq)]
+pub struct Arrival {
+    /// External id of the user, arrivals with the same key belong to the same user
+    pub key: Option<u64>,
+    pub real_skill: f32,
+    /// Rating the user starts with, the default skill level is used if not set
+    pub skill: Option<f32>,
+    pub party: Option<u32>,
+    pub segment: Segment,
+    /// Uncertainty of the starting rating, scales the rating updates
+    pub rating_deviation: Option<f32>,
+    /// Relative activity of the user, scales how long the user keeps playing
+    pub activity: Option<f32>,
+}
+
+pub trait ArrivalGenerator: Debug + Send {
+    fn arrivals(&mut self, tick: u32, rng: &mut SimRng) -> Vec<Arrival>;
+}
+
+/// Multiplier of the base arrival rate, repeats every `period` ticks
+#[derive(Debug)]
+pub enum RateProfile {
+    Constant,
+    /// Sinusoid with the given relative amplitude, reaching its maximum at the `peak` tick of the period
+    Diurnal { amplitude: f32, peak: u32, period: u32 },
+    /// Piecewise constant multipliers, each applied from its tick until the next one
+    Schedule { points: Vec<(u32, f32)>, period: u32 },
+}
+
+impl RateProfile {
+    pub fn multiplier(&self, tick: u32) -> f32 {
+        match *self {
+            RateProfile::Constant => 1.0,
+            RateProfile::Diurnal { amplitude, peak, period } => {
+                let phase = (tick % period) as f32 - (peak % period) as f32;
+                (1.0 + amplitude * (2.0 * PI * phase / period as f32).cos()).max(0.0)
+            }
+            RateProfile::Schedule { ref points, period } => {
+                let tick = tick % period;
+                points.iter()
+                    .take_while(|point| point.0 <= tick)
+                    .last()
+                    .or_else(|| points.last())
+                    .map(|point| point.1)
+                    .unwrap_or(1.0)
+            }
+        }
+    }
+
+    /// Reads `tick,multiplier` lines
+    pub fn load_schedule(path: &str, period: u32) -> RateProfile {
+        let content = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Unable to read arrival profile {}: {}", path, e));
+
+        let mut points: Vec<(u32, f32)> = data_lines(&content, 2, "arrival profile")
+            .map(|values| (values[0].parse().unwrap(), values[1].parse().unwrap()))
+            .collect();
+        points.sort_by_key(|point| point.0);
+
+        RateProfile::Schedule { points, period }
+    }
+}
+
+/// Multiplies the arrival rate by `factor` for `duration` ticks, e.g. a patch day
+#[derive(Debug)]
+pub struct Burst {
+    pub start: u32,
+    pub duration: u32,
+    pub factor: f32,
+}
+
+impl Burst {
+    /// Parses `start:duration:factor`
+    pub fn parse(value: &str) -> Burst {
+        let values: Vec<&str> = value.split(':').collect();
+        if values.len() != 3 {
+            panic!("Malformed burst, start:duration:factor expected: {}", value);
+        }
+
+        Burst {
+            start: values[0].parse().unwrap(),
+            duration: values[1].parse().unwrap(),
+            factor: values[2].parse().unwrap(),
+        }
+    }
+}
+
+/// Share of the generated users belonging to a segment, and how far their skill is off
+#[derive(Debug)]
+pub struct SegmentShare {
+    pub segment: Segment,
+    pub share: f32,
+    /// Smurfs are better than their generated real skill level by the offset,
+    /// veterans are rated higher than their real skill level by the offset
+    pub offset: f32,
+}
+
+/// Generates `users_at_start` users on the first tick, or enters the imported population instead,
+/// then Poisson arrivals with the base rate shaped by the profile and the bursts
+#[derive(Debug)]
+pub struct SyntheticArrivals {
+    pub users_at_start: u32,
+    pub users_per_tick: f32,
+    pub real_skill_gen: RandomRangeGen,
+    pub profile: RateProfile,
+    pub bursts: Vec<Burst>,
+    pub segments: Vec<SegmentShare>,
+    pub population: Option<Population>,
+
+    started: bool,
+}
+
+impl SyntheticArrivals {
+    pub fn new(users_at_start: u32, users_to_gen: u32, ticks: u32, real_skill_gen: RandomRangeGen, profile: RateProfile, bursts: Vec<Burst>) -> SyntheticArrivals {
+        SyntheticArrivals {
+            users_at_start,
+            users_per_tick: (users_to_gen as f32) / (ticks as f32),
+            real_skill_gen,
+            profile,
+            bursts,
+            segments: Vec::new(),
+            population: None,
+            started: false,
+        }
+    }
+
+    pub fn rate(&self, tick: u32) -> f32 {
+        let bursts = self.bursts.iter()
+            .filter(|burst| burst.start <= tick && tick < burst.start + burst.duration)
+            .fold(1.0, |factor, burst| factor * burst.factor);
+
+        self.users_per_tick * self.profile.multiplier(tick) * bursts
+    }
+}
+
+impl ArrivalGenerator for SyntheticArrivals {
+    fn arrivals(&mut self, tick: u32, rng: &mut SimRng) -> Vec<Arrival> {
+        let mut arrivals = Vec::new();
+
+        let mut users_to_gen = poisson(self.rate(tick), rng);
+        if !self.started {
+            self.started = true;
+            match self.population {
+                Some(ref population) => arrivals.extend(population.entries.iter().map(|entry| Arrival {
+                    key: None,
+                    real_skill: entry.real_skill,
+                    skill: Some(entry.rating),
+                    party: None,
+                    segment: Segment::Regular,
+                    rating_deviation: entry.rating_deviation,
+                    activity: entry.activity,
+                })),
+                None => users_to_gen += self.users_at_start,
+            }
+        }
+
+        for _ in 0..users_to_gen {
+            let real_skill = self.real_skill_gen.generate(rng);
+
+            let mut draw = if self.segments.is_empty() { 1.0 } else { rng.next_f32() };
+            let segment = self.segments.iter().find(|segment| {
+                draw -= segment.share;
+                draw < 0.0
+            });
+
+            arrivals.push(match segment {
+                Some(&SegmentShare { segment: Segment::Smurf, offset, .. }) => Arrival {
+                    key: None,
+                    real_skill: real_skill + offset,
+                    skill: None,
+                    party: None,
+                    segment: Segment::Smurf,
+                    rating_deviation: None,
+                    activity: None,
+                },
+                Some(&SegmentShare { segment: Segment::Veteran, offset, .. }) => Arrival {
+                    key: None,
+                    real_skill,
+                    skill: Some(real_skill + offset),
+                    party: None,
+                    segment: Segment::Veteran,
+                    rating_deviation: None,
+                    activity: None,
+                },
+                _ => Arrival {
+                    key: None,
+                    real_skill,
+                    skill: None,
+                    party: None,
+                    segment: Segment::Regular,
+                    rating_deviation: None,
+                    activity: None,
+                },
+            });
+        }
+
+        arrivals
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PopulationEntry {
+    pub real_skill: f32,
+    pub rating: f32,
+    pub rating_deviation: Option<f32>,
+    pub activity: Option<f32>,
+}
+
+/// Existing players entering on the first tick, the entries are shared between the runs
+#[derive(Clone)]
+pub struct Population {
+    pub path: String,
+    entries: Arc<Vec<PopulationEntry>>,
+}
+
+impl Population {
+    pub fn load(path: &str) -> Population {
+        let content = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Unable to read population {}: {}", path, e));
+
+        Population {
+            path: path.to_owned(),
+            entries: Arc::new(parse_population(&content)),
+        }
+    }
+}
+
+impl Debug for Population {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Population {{ path: {}, entries: {} }}", self.path, self.entries.len())
+    }
+}
+
+/// Parses `real_skill,rating[,rating_deviation[,activity]]` lines
+pub fn parse_population(content: &str) -> Vec<PopulationEntry> {
+    data_lines(content, 2, "population")
+        .map(|values| {
+            let optional = |index: usize| values.get(index).cloned().filter(|v| !v.is_empty());
+
+            PopulationEntry {
+                real_skill: values[0].parse().unwrap(),
+                rating: values[1].parse().unwrap(),
+                rating_deviation: optional(2).map(|v| v.parse().unwrap()),
+                activity: optional(3).map(|v| v.parse().unwrap()),
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TraceEntry {
+    pub tick: u32,
+    pub user: u64,
+    pub real_skill: f32,
+    pub skill: Option<f32>,
+    pub party: Option<u32>,
+}
+
+/// Replays arrivals recorded in a trace file, the entries are shared between the runs
+#[derive(Clone)]
+pub struct TraceArrivals {
+    pub path: String,
+    entries: Arc<Vec<TraceEntry>>,
+    position: usize,
+}
+
+impl TraceArrivals {
+    pub fn load(path: &str) -> TraceArrivals {
+        let content = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Unable to read arrivals trace {}: {}", path, e));
+
+        TraceArrivals::new(path, parse_trace(&content))
+    }
+
+    pub fn new(path: &str, entries: Vec<TraceEntry>) -> TraceArrivals {
+        TraceArrivals {
+            path: path.to_owned(),
+            entries: Arc::new(entries),
+            position: 0,
+        }
+    }
+}
+
+impl Debug for TraceArrivals {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TraceArrivals {{ path: {}, entries: {} }}", self.path, self.entries.len())
+    }
+}
+
+impl ArrivalGenerator for TraceArrivals {
+    fn arrivals(&mut self, tick: u32, _: &mut SimRng) -> Vec<Arrival> {
+        let mut arrivals = Vec::new();
+
+        while let Some(entry) = self.entries.get(self.position) {
+            if entry.tick > tick {
+                break;
+            }
+
+            arrivals.push(Arrival {
+                key: Some(entry.user),
+                real_skill: entry.real_skill,
+                skill: entry.skill,
+                party: entry.party,
+                segment: Segment::Regular,
+                rating_deviation: None,
+                activity: None,
+            });
+            self.position += 1;
+        }
+
+        arrivals
+    }
+}
+
+/// Parses `tick,user_id,real_skill[,rating[,party]]` lines
+pub fn parse_trace(content: &str) -> Vec<TraceEntry> {
+    let mut entries: Vec<TraceEntry> = data_lines(content, 3, "arrivals trace")
+        .map(|values| {
+            let optional = |index: usize| values.get(index).cloned().filter(|v| !v.is_empty());
+
+            TraceEntry {
+                tick: values[0].parse().unwrap(),
+                user: values[1].parse().unwrap(),
+                real_skill: values[2].parse().unwrap(),
+                skill: optional(3).map(|v| v.parse().unwrap()),
+                party: optional(4).map(|v| v.parse().unwrap()),
+            }
+        })
+        .collect();
+
+    // the replay relies on the entries being ordered by tick
+    entries.sort_by_key(|entry| entry.tick);
+    entries
+}
+
+// ============================ TESTS ============================
+
+#[test]
+fn diurnal_profile() {
+    let profile = RateProfile::Diurnal { amplitude: 0.5, peak: 100, period: 400 };
+
+    assert!((profile.multiplier(100) - 1.5).abs() < 0.001);
+    assert!((profile.multiplier(300) - 0.5).abs() < 0.001);
+    assert!((profile.multiplier(500) - 1.5).abs() < 0.001);
+}
+
+#[test]
+fn schedule_profile_and_bursts() {
+    let profile = RateProfile::Schedule { points: vec![(10, 2.0), (20, 0.5)], period: 30 };
+    let bursts = vec![Burst::parse("40:5:3")];
+    let arrivals = SyntheticArrivals::new(0, 30, 30, RandomRangeGen::new(0.0, 1.0, ::entities::DistributionType::Uniform), profile, bursts);
+
+    // before the first point the last one of the previous period is used
+    assert_eq!(arrivals.rate(5), 0.5);
+    assert_eq!(arrivals.rate(15), 2.0);
+    assert_eq!(arrivals.rate(25), 0.5);
+    assert_eq!(arrivals.rate(42), 6.0);
+    assert_eq!(arrivals.rate(45), 2.0);
+}
+
+#[test]
+fn segments() {
+    let real_skill_gen = RandomRangeGen::new(1000.0, 1000.0, ::entities::DistributionType::Uniform);
+    let mut arrivals = SyntheticArrivals::new(1000, 0, 1, real_skill_gen, RateProfile::Constant, Vec::new());
+    arrivals.segments = vec![
+        SegmentShare { segment: Segment::Smurf, share: 0.2, offset: 700.0 },
+        SegmentShare { segment: Segment::Veteran, share: 0.1, offset: 300.0 },
+    ];
+
+    let users = arrivals.arrivals(1, &mut ::entities::new_rng(3));
+    let smurfs: Vec<&Arrival> = users.iter().filter(|user| user.segment == Segment::Smurf).collect();
+    let veterans: Vec<&Arrival> = users.iter().filter(|user| user.segment == Segment::Veteran).collect();
+
+    assert!((smurfs.len() as i32 - 200).abs() < 40);
+    assert!((veterans.len() as i32 - 100).abs() < 30);
+    assert!(smurfs.iter().all(|user| user.real_skill == 1700.0 && user.skill.is_none()));
+    assert!(veterans.iter().all(|user| user.real_skill == 1000.0 && user.skill == Some(1300.0)));
+}
+
+#[test]
+fn trace_parse() {
+    let entries = parse_trace("# tick,user,real_skill,rating,party\n5,2,1800.5\n\n1,7,1200,1450,3\n3,2,1800.5,,4\n");
+
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0], TraceEntry { tick: 1, user: 7, real_skill: 1200.0, skill: Some(1450.0), party: Some(3) });
+    assert_eq!(entries[1], TraceEntry { tick: 3, user: 2, real_skill: 1800.5, skill: None, party: Some(4) });
+    assert_eq!(entries[2], TraceEntry { tick: 5, user: 2, real_skill: 1800.5, skill: None, party: None });
+}
+
+#[test]
+fn trace_replay_by_tick() {
+    let mut trace = TraceArrivals {
+        path: String::new(),
+        entries: Arc::new(parse_trace("1,1,1000\n1,2,1100\n4,3,1200\n")),
+        position: 0,
+    };
+    let mut rng = ::entities::new_rng(0);
+
+    assert_eq!(trace.arrivals(1, &mut rng).len(), 2);
+    assert_eq!(trace.arrivals(2, &mut rng).len(), 0);
+    assert_eq!(trace.arrivals(4, &mut rng)[0].key, Some(3));
+    assert_eq!(trace.arrivals(5, &mut rng).len(), 0);
+}
+
+#[test]
+fn population_replaces_users_at_start() {
+    let real_skill_gen = RandomRangeGen::new(1000.0, 1000.0, ::entities::DistributionType::Uniform);
+    let mut arrivals = SyntheticArrivals::new(1000, 0, 1, real_skill_gen, RateProfile::Constant, Vec::new());
+    arrivals.population = Some(Population {
+        path: String::new(),
+        entries: Arc::new(parse_population("# real_skill,rating,rd,activity\n1800,1650,80,0.5\n\n1200,1300\n1500,1500,,2\n")),
+    });
+
+    let users = arrivals.arrivals(1, &mut ::entities::new_rng(0));
+
+    assert_eq!(users.len(), 3);
+    assert_eq!(users[0].real_skill, 1800.0);
+    assert_eq!(users[0].skill, Some(1650.0));
+    assert_eq!(users[0].rating_deviation, Some(80.0));
+    assert_eq!(users[0].activity, Some(0.5));
+    assert_eq!(users[1].rating_deviation, None);
+    assert_eq!(users[2].activity, Some(2.0));
+}
@@ -0,0 +1,24 @@
+/// Trimmed values of the lines of a comma separated data file, e.g. a trace or a skill histogram.
+/// Empty lines and lines starting with `#` are skipped, a line with less than `columns` values panics.
+pub fn data_lines<'a>(content: &'a str, columns: usize, what: &'a str) -> impl Iterator<Item = Vec<&'a str>> + 'a {
+    content.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(move |line| {
+            let values: Vec<&str> = line.split(',').map(|v| v.trim()).collect();
+            if values.len() < columns {
+                panic!("Malformed {} entry: {}", what, line);
+            }
+
+            values
+        })
+}
+
+// ============================ TESTS ============================
+
+#[test]
+fn data_lines_skip_comments() {
+    let lines: Vec<Vec<&str>> = data_lines("# tick,multiplier\n\n 10, 2.5 \n20,0.5,\n", 2, "test").collect();
+
+    assert_eq!(lines, vec![vec!["10", "2.5"], vec!["20", "0.5", ""]]);
+}
@@ -1,19 +1,107 @@
-use rand::{thread_rng, Rng};
+use data::data_lines;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+#[cfg(test)]
+use rand::thread_rng;
 
 use std::f32::consts::PI;
-use std::cell::Cell;
 
 use std::fmt::Debug;
 
 pub type UserId = usize;
 
+/// Random generator used by the simulation, every run owns its own seeded instance
+pub type SimRng = XorShiftRng;
+
+pub fn new_rng(seed: u64) -> SimRng {
+    // xorshift must not be seeded with zeros, so the seed is spread with splitmix64 first
+    let a = splitmix64(seed);
+    let b = splitmix64(a);
+
+    SimRng::from_seed([a as u32, (a >> 32) as u32, b as u32, (b >> 32) as u32 | 1])
+}
+
+/// Derives an independent seed for the sub-run with the given index
+pub fn derive_seed(seed: u64, index: u64) -> u64 {
+    splitmix64(seed ^ splitmix64(index.wrapping_add(1)))
+}
+
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UserState {
+    Offline,
+    Queued,
+    Playing,
+}
+
+/// Population segment, the segments differ in how the initial skill relates to the real skill
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Segment {
+    Regular,
+    /// Experienced player on a new account, starts at the default skill level
+    Smurf,
+    /// Player returning after a long break with a stale rating
+    Veteran,
+}
+
+impl Segment {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Segment::Regular => "regular",
+            Segment::Smurf => "smurf",
+            Segment::Veteran => "veteran",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct UserData {
     pub id: UserId,
     pub real_skill: f32,
-
-    skill: Cell<f32>,
-    join_time: Cell<u32>,
+    pub party: Option<u32>,
+    pub segment: Segment,
+    pub state: UserState,
+    /// Amount of ticks the user waits in the queue before giving up
+    pub patience: f32,
+    pub created_tick: u32,
+    /// Amount of games the user plays before logging out, sessions are not modeled if zero
+    pub session_length: u32,
+    pub session_games: u32,
+    /// Tick the user went offline at, used to apply the inactivity decay of the real skill level
+    pub offline_since: Option<u32>,
+    /// Amount of games it took the skill level to get close to the real skill level
+    pub converged_after: Option<u32>,
+    /// Uncertainty of an imported rating, the rating of an uncertain user moves faster.
+    /// It moves toward the deviation of an unrated player with every game.
+    pub rating_deviation: Option<f32>,
+    /// Relative activity of the user, scales the probability to keep playing and the session length
+    pub activity: f32,
+    /// Amount of games the user has to play in the low priority queue after leaving a game
+    pub low_priority_games: u32,
+    /// Amount of ready checks the user has answered
+    pub ready_checks: u32,
+    /// Amount of games the user entered, including the ones left, it indexes the draws of the user's decisions
+    pub games_entered: u32,
+    /// Tick of the first failed ready check since the user joined the queue
+    pub failed_check_at: Option<u32>,
+    pub history: UserHistory,
+    /// The user left for good
+    pub churned: bool,
+
+    // experience of the last game, drives the retention
+    pub loss_streak: u32,
+    pub last_wait: u32,
+    pub last_imbalance: f32,
+
+    skill: f32,
+    join_time: u32,
+    games_played: u32,
 
     use_real_skill: bool,
 }
@@ -21,46 +109,91 @@ pub struct UserData {
 impl UserData {
     fn new(id: UserId, initial_skill: f32, real_skill: f32, use_real_skill: bool) -> UserData {
         UserData {
-            id: id,
-            skill: Cell::new(initial_skill),
-            real_skill: real_skill,
-            join_time: Cell::new(0),
-            use_real_skill: use_real_skill,
+            id,
+            skill: initial_skill,
+            real_skill,
+            party: None,
+            segment: Segment::Regular,
+            state: UserState::Offline,
+            patience: f32::INFINITY,
+            created_tick: 0,
+            session_length: 0,
+            session_games: 0,
+            offline_since: None,
+            converged_after: None,
+            rating_deviation: None,
+            activity: 1.0,
+            low_priority_games: 0,
+            ready_checks: 0,
+            games_entered: 0,
+            failed_check_at: None,
+            history: UserHistory::default(),
+            churned: false,
+            loss_streak: 0,
+            last_wait: 0,
+            last_imbalance: 0.0,
+            join_time: 0,
+            games_played: 0,
+            use_real_skill,
         }
     }
 
-    pub fn set_join_time(&self, join_time: u32) {
-        self.join_time.set(join_time);
+    pub fn set_join_time(&mut self, join_time: u32) {
+        self.join_time = join_time;
     }
 
     pub fn get_join_time(&self) -> u32 {
-        self.join_time.get()
+        self.join_time
+    }
+
+    pub fn get_games_played(&self) -> u32 {
+        self.games_played
+    }
+
+    pub fn add_game_played(&mut self) {
+        self.games_played += 1;
     }
 
-    pub fn update_skill(&self, delta: f32) {
+    pub fn update_skill(&mut self, delta: f32) {
         if self.use_real_skill {
             return;
         }
 
-        self.skill.set(self.skill.get() + delta);
+        self.skill += delta;
     }
 
     pub fn get_skill(&self) -> f32 {
-        if self.use_real_skill { self.real_skill } else { self.skill.get() }
+        if self.use_real_skill { self.real_skill } else { self.skill }
     }
 }
 
+/// What the user experienced during the run
+#[derive(Debug, Default)]
+pub struct UserHistory {
+    pub wins: u32,
+    pub losses: u32,
+    /// Ticks spent in the queue before the games
+    pub total_wait: u32,
+    /// Sum of the real skill gaps between the teams of the games
+    pub total_imbalance: f32,
+    /// First tick the rating got close to the real skill level
+    pub converged_at: Option<u32>,
+}
+
 #[derive(Debug)]
 pub struct UserPool {
     users: Vec<UserData>,
     pub use_real_skill: bool,
+    /// Elo K factor of the rating updates
+    pub k_factor: f32,
 }
 
 impl UserPool {
     pub fn new(use_real_skill: bool) -> UserPool {
         UserPool {
             users: Vec::new(),
-            use_real_skill: use_real_skill,
+            use_real_skill,
+            k_factor: 32.0,
         }
     }
 
@@ -74,10 +207,32 @@ impl UserPool {
         &self.users[*id]
     }
 
+    pub fn get_user_mut(&mut self, id: &UserId) -> &mut UserData {
+        &mut self.users[*id]
+    }
+
+    pub fn users(&self) -> &[UserData] {
+        &self.users
+    }
+
     pub fn get_avg_skill_error(&self) -> f32 {
         let sum = self.users.iter().fold(0.0, |sum, data| sum + (data.real_skill - data.get_skill()).abs());
         sum / (self.users.len() as f32)
     }
+
+    /// Average skill level error of the users matching the filter
+    pub fn get_avg_skill_error_of<F: Fn(&UserData) -> bool>(&self, filter: F) -> f32 {
+        let (sum, count) = self.users.iter()
+            .filter(|data| filter(data))
+            .fold((0.0, 0), |(sum, count), data| (sum + (data.real_skill - data.get_skill()).abs(), count + 1));
+
+        if count == 0 { 0.0 } else { sum / (count as f32) }
+    }
+
+    pub fn get_avg_real_skill(&self) -> f32 {
+        let sum = self.users.iter().fold(0.0, |sum, data| sum + data.real_skill);
+        sum / (self.users.len() as f32)
+    }
 }
 
 #[derive(PartialEq)]
@@ -85,44 +240,83 @@ impl UserPool {
 pub struct Game {
     pub team1: Vec<UserId>,
     pub team2: Vec<UserId>,
+    /// Players who joined the game in progress in place of leavers, they are members of the teams as well
+    pub backfilled: Vec<UserId>,
 }
 
 impl Game {
     pub fn new(team1: Vec<UserId>, team2: Vec<UserId>) -> Game {
         Game {
-            team1: team1,
-            team2: team2,
+            team1,
+            team2,
+            backfilled: Vec::new(),
+        }
+    }
+
+    pub fn backfill(&mut self, team: u32, id: UserId) {
+        match team {
+            1 => self.team1.push(id),
+            2 => self.team2.push(id),
+            _ => panic!()
         }
+        self.backfilled.push(id);
+    }
+
+    /// Predicted probability that the first team wins, by the average skill levels of the teams
+    pub fn win_probability(&self, user_pool: &UserPool) -> f32 {
+        win_probability(team_skill(&self.team1, user_pool), team_skill(&self.team2, user_pool))
     }
 
-    pub fn process(&self, user_pool: &UserPool, win_team: u32) {
-        let (winners, losers) = match win_team {
-            1 => (&self.team1, &self.team2),
-            2 => (&self.team2, &self.team1),
+    /// Updates the skill levels of the players, returns the rating deltas of the first and the second team
+    pub fn process(&self, user_pool: &mut UserPool, win_team: u32) -> (Vec<f32>, Vec<f32>) {
+        let team1_win = match win_team {
+            1 => 1.0,
+            2 => 0.0,
             _ => panic!()
         };
 
-        let winners_avg = winners.iter().fold(0.0, |sum, id| sum + user_pool.get_user(id).get_skill()) / (winners.len() as f32);
-        let losers_avg = losers.iter().fold(0.0, |sum, id| sum + user_pool.get_user(id).get_skill()) / (losers.len() as f32);
+        let e_team1 = self.win_probability(user_pool);
+        let base_k_factor = user_pool.k_factor;
+
+        let mut update = |team: &[UserId], result: f32, expected: f32| -> Vec<f32> {
+            team.iter()
+                .map(|id| {
+                    let user = user_pool.get_user_mut(id);
+                    let delta = k_factor(user, base_k_factor) * (result - expected);
+                    user.update_skill(delta);
+                    settle_deviation(user);
+                    delta
+                })
+                .collect()
+        };
+
+        let team1_deltas = update(&self.team1, team1_win, e_team1);
+        let team2_deltas = update(&self.team2, 1.0 - team1_win, 1.0 - e_team1);
 
-        let r_win = 10.0_f32.powf(winners_avg / 400.0);
-        let r_lose = 10.0_f32.powf(losers_avg / 400.0);
+        (team1_deltas, team2_deltas)
+    }
+}
 
-        let e_win = r_win / (r_win + r_lose);
-        let e_lose = r_lose / (r_win + r_lose);
+fn team_skill(team: &[UserId], user_pool: &UserPool) -> f32 {
+    team.iter().fold(0.0, |sum, id| sum + user_pool.get_user(id).get_skill()) / (team.len() as f32)
+}
 
-        static K_FACTOR: f32 = 32.0;
+/// Elo expected score of a team with the first skill level against a team with the second one
+pub fn win_probability(skill: f32, opponent_skill: f32) -> f32 {
+    1.0 / (1.0 + 10.0_f32.powf((opponent_skill - skill) / 400.0))
+}
 
-        let winner_delta = K_FACTOR * (1.0 - e_win);
-        let loser_delta = K_FACTOR * (-e_lose);
+/// Rating deviation of an unrated player, the deviation the base K factor corresponds to
+const UNRATED_DEVIATION: f32 = 350.0;
 
-        for id in winners {
-            user_pool.get_user(id).update_skill(winner_delta);
-        }
+/// Share of the distance to the unrated deviation left after a game
+const DEVIATION_DECAY: f32 = 0.9;
 
-        for id in losers {
-            user_pool.get_user(id).update_skill(loser_delta);
-        }
+/// Elo K factor of the user, scaled by the rating deviation relative to the deviation of an unrated player
+fn k_factor(user: &UserData, k_factor: f32) -> f32 {
+    match user.rating_deviation {
+        Some(deviation) => k_factor * deviation / UNRATED_DEVIATION,
+        None => k_factor,
     }
 }
 
@@ -133,58 +327,102 @@ pub enum AlgorithmResult {
     Found(Game),
 }
 
-pub trait GameDecider: Debug {
-    fn decide(&self, game: &Game, pool: &UserPool) -> u32;
+pub trait GameDecider: Debug + Send {
+    fn decide(&self, game: &Game, pool: &UserPool, rng: &mut SimRng) -> u32;
 }
 
 #[derive(Debug)]
 pub struct RealSkillLevelDecider {}
 
 impl GameDecider for RealSkillLevelDecider {
-    fn decide(&self, game: &Game, pool: &UserPool) -> u32 {
+    fn decide(&self, game: &Game, pool: &UserPool, rng: &mut SimRng) -> u32 {
         let skill1 = game.team1.iter().fold(0.0, |sum, id| sum + pool.get_user(id).real_skill);
         let skill2 = game.team2.iter().fold(0.0, |sum, id| sum + pool.get_user(id).real_skill);
 
         if skill1 == skill2 {
-            if thread_rng().gen() {
-                return 1;
-            } else {
-                return 2;
-            }
+            return if rng.gen() { 1 } else { 2 };
         }
 
-        if skill1 > skill2 {
-            return 1;
-        } else {
-            return 2;
-        }
+        if skill1 > skill2 { 1 } else { 2 }
     }
 }
 
-pub trait Algoritm: Debug {
-    fn search(&self, queue: &mut Vec<UserId>, pool: &UserPool) -> AlgorithmResult;
+pub trait Algoritm: Debug + Send {
+    fn search(&self, queue: &mut Vec<UserId>, pool: &UserPool, rng: &mut SimRng) -> AlgorithmResult;
+}
+
+/// Place of a leaver in a game in progress
+#[derive(Debug)]
+pub struct BackfillSlot {
+    /// Skill level of the player who left
+    pub skill: f32,
+    /// Ticks left until the end of the game
+    pub remaining: u32,
+    pub length: u32,
+}
+
+pub trait BackfillPolicy: Debug + Send {
+    /// Index of the queued user taking the slot, `None` if nobody fits
+    fn pick(&self, queue: &[UserId], pool: &UserPool, slot: &BackfillSlot) -> Option<usize>;
+}
+
+/// Picks the queued user with the skill level closest to the leaver, games close to their end are not backfilled
+#[derive(Debug)]
+pub struct SkillProximityBackfill {
+    pub max_skill_delta: f32,
+    /// Share of the game that must be left
+    pub min_remaining: f32,
+}
+
+impl BackfillPolicy for SkillProximityBackfill {
+    fn pick(&self, queue: &[UserId], pool: &UserPool, slot: &BackfillSlot) -> Option<usize> {
+        if (slot.remaining as f32) < self.min_remaining * (slot.length as f32) {
+            return None;
+        }
+
+        queue.iter().enumerate()
+            .map(|(index, id)| (index, (pool.get_user(id).get_skill() - slot.skill).abs()))
+            .filter(|&(_, delta)| delta <= self.max_skill_delta)
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+            .map(|(index, _)| index)
+    }
 }
 
 pub struct SimpleUserGenerator {
     pub skill: f32,
 }
 
-pub fn peek_random<T>(vec: &mut Vec<T>) -> Option<T> {
-    if vec.len() == 0 {
+pub fn peek_random<T>(vec: &mut Vec<T>, rng: &mut SimRng) -> Option<T> {
+    if vec.is_empty() {
         return Option::None;
     }
 
-    let index = thread_rng().gen_range(0, vec.len());
+    let index = rng.gen_range(0, vec.len());
     Option::Some(vec.remove(index))
 }
 
+/// Returns a user to the queue, either to its end or, keeping the position, among the users by join time
+pub fn requeue(queue: &mut Vec<UserId>, pool: &UserPool, id: UserId, keep_position: bool) {
+    if !keep_position {
+        queue.push(id);
+        return;
+    }
+
+    // the queue is ordered by join time
+    let join_time = pool.get_user(&id).get_join_time();
+    let position = queue.iter()
+        .position(|other| pool.get_user(other).get_join_time() > join_time)
+        .unwrap_or(queue.len());
+    queue.insert(position, id);
+}
+
 #[derive(Debug)]
 pub struct RandomPeekAlgorithm {
     pub team_size: usize,
 }
 
 impl Algoritm for RandomPeekAlgorithm {
-    fn search(&self, queue: &mut Vec<UserId>, _: &UserPool) -> AlgorithmResult {
+    fn search(&self, queue: &mut Vec<UserId>, _: &UserPool, rng: &mut SimRng) -> AlgorithmResult {
         if queue.len() < (self.team_size * 2) {
             return AlgorithmResult::None;
         }
@@ -193,8 +431,8 @@ impl Algoritm for RandomPeekAlgorithm {
         let mut team2 = Vec::new();
 
         for _ in 0..self.team_size {
-            team1.push(peek_random(queue).unwrap());
-            team2.push(peek_random(queue).unwrap());
+            team1.push(peek_random(queue, rng).unwrap());
+            team2.push(peek_random(queue, rng).unwrap());
         }
 
         AlgorithmResult::Found(Game::new(team1, team2))
@@ -209,7 +447,7 @@ pub struct SkillLevelAlgorithm {
 }
 
 impl Algoritm for SkillLevelAlgorithm {
-    fn search(&self, queue: &mut Vec<UserId>, pool: &UserPool) -> AlgorithmResult {
+    fn search(&self, queue: &mut Vec<UserId>, pool: &UserPool, _: &mut SimRng) -> AlgorithmResult {
         if (queue.len() as f32) < ((self.team_size as f32) * self.size_factor * 2.0) {
             return AlgorithmResult::None;
         }
@@ -246,9 +484,9 @@ impl Algoritm for SkillLevelAlgorithm {
 
             let (index, _) = queue.iter().enumerate()
                 .map(|(index, id)| (index, (pool.get_user(id).get_skill() - desired_skill).abs()))
-                .fold((usize::max_value(), 1.0 / 0.0), |i, v| if v.1 > i.1 { i } else { (v.0, v.1) });
+                .fold((usize::MAX, 1.0 / 0.0), |i, v| if v.1 > i.1 { i } else { (v.0, v.1) });
 
-            assert!(index != usize::max_value());
+            assert!(index != usize::MAX);
 
             let candidate = queue.remove(index);
 
@@ -265,7 +503,7 @@ pub struct FIFOAlgorithm {
 }
 
 impl Algoritm for FIFOAlgorithm {
-    fn search(&self, queue: &mut Vec<UserId>, _: &UserPool) -> AlgorithmResult {
+    fn search(&self, queue: &mut Vec<UserId>, _: &UserPool, _: &mut SimRng) -> AlgorithmResult {
         if queue.len() < (self.team_size * 2) {
             return AlgorithmResult::None;
         }
@@ -286,9 +524,16 @@ impl Algoritm for FIFOAlgorithm {
 #[derive(Debug)]
 pub enum DistributionType {
     Uniform,
-    Normal
+    Normal { mean: f32, sigma: f32 },
+    /// Log-normal distribution of the distance from the minimum, the parameters describe the values
+    LogNormal { mean: f32, sigma: f32 },
+    /// Normal distributions as `(weight, mean, sigma)`, e.g. a bimodal population
+    Mixture(Vec<(f32, f32, f32)>),
+    /// Histogram as `(bucket start, weight)` sorted by the bucket start, values are uniform within a bucket
+    Empirical(Vec<(f32, f32)>),
 }
 
+/// Generates values of the distribution truncated to the range, values outside of it are rejected
 #[derive(Debug)]
 pub struct RandomRangeGen {
     min: f32,
@@ -299,34 +544,251 @@ pub struct RandomRangeGen {
 impl RandomRangeGen {
     pub fn new(min: f32, max: f32, distribution: DistributionType) -> RandomRangeGen {
         RandomRangeGen {
-            min: min,
-            max: max,
-            distribution: distribution,
+            min,
+            max,
+            distribution,
         }
     }
 
-    pub fn generate(&self) -> f32 {
-        let rand = match self.distribution {
-            DistributionType::Uniform => (thread_rng().next_f32()),
-            DistributionType::Normal => {
-                let u1 = thread_rng().next_f32();
-                let u2 = thread_rng().next_f32();
-                // https://en.wikipedia.org/wiki/Box%E2%80%93Muller_transform
-                let mut result = (-2.0 * u1.ln()).sqrt() * ((2.0 * PI * u2).cos());
+    pub fn generate(&self, rng: &mut SimRng) -> f32 {
+        if let DistributionType::Uniform = self.distribution {
+            return ((self.max - self.min) * rng.next_f32()) + self.min;
+        }
+
+        // the attempts are limited in case the range holds almost no mass of the distribution
+        static MAX_ATTEMPTS: u32 = 1000;
 
-                if result > 1.0 {
-                    result = 1.0;
-                }
+        let mut value = self.sample(rng);
+        for _ in 0..MAX_ATTEMPTS {
+            if value >= self.min && value <= self.max {
+                return value;
+            }
+            value = self.sample(rng);
+        }
 
-                if result < -1.0 {
-                    result = -1.0;
-                }
+        value.clamp(self.min, self.max)
+    }
 
-                    (result + 1.0) * 0.5
+    fn sample(&self, rng: &mut SimRng) -> f32 {
+        match self.distribution {
+            DistributionType::Uniform => ((self.max - self.min) * rng.next_f32()) + self.min,
+            DistributionType::Normal { mean, sigma } => mean + sigma * standard_normal(rng),
+            DistributionType::LogNormal { mean, sigma } => {
+                // https://en.wikipedia.org/wiki/Log-normal_distribution#Arithmetic_moments
+                let distance = (mean - self.min).max(f32::EPSILON);
+                let log_sigma = (1.0 + (sigma * sigma) / (distance * distance)).ln().sqrt();
+                let log_mean = distance.ln() - log_sigma * log_sigma / 2.0;
+
+                self.min + (log_mean + log_sigma * standard_normal(rng)).exp()
             }
-        };
+            DistributionType::Mixture(ref modes) => {
+                let total: f32 = modes.iter().map(|mode| mode.0).sum();
+                let mut draw = rng.next_f32() * total;
+
+                let &(_, mean, sigma) = modes.iter()
+                    .find(|mode| {
+                        draw -= mode.0;
+                        draw < 0.0
+                    })
+                    .unwrap_or_else(|| modes.last().unwrap());
+
+                mean + sigma * standard_normal(rng)
+            }
+            DistributionType::Empirical(ref buckets) => {
+                let total: f32 = buckets.iter().map(|bucket| bucket.1).sum();
+                let mut draw = rng.next_f32() * total;
+
+                let index = buckets.iter()
+                    .position(|bucket| {
+                        draw -= bucket.1;
+                        draw < 0.0
+                    })
+                    .unwrap_or(buckets.len() - 1);
+
+                // the last bucket is as wide as the one before it
+                let start = buckets[index].0;
+                let end = match buckets.get(index + 1) {
+                    Some(next) => next.0,
+                    None if index > 0 => start + (start - buckets[index - 1].0),
+                    None => start,
+                };
+
+                start + (end - start) * rng.next_f32()
+            }
+        }
+    }
+}
+
+/// Parses `bucket start,weight` lines of a histogram
+pub fn parse_histogram(content: &str) -> Vec<(f32, f32)> {
+    let mut buckets: Vec<(f32, f32)> = data_lines(content, 2, "histogram")
+        .map(|values| (values[0].parse().unwrap(), values[1].parse().unwrap()))
+        .collect();
+
+    if buckets.is_empty() {
+        panic!("Empty histogram");
+    }
+
+    buckets.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    buckets
+}
+
+/// Generates the patience of the users, in ticks
+#[derive(Debug)]
+pub enum PatienceGen {
+    Unlimited,
+    Range(RandomRangeGen),
+    Exponential { mean: f32 },
+}
+
+impl PatienceGen {
+    pub fn generate(&self, rng: &mut SimRng) -> f32 {
+        match *self {
+            PatienceGen::Unlimited => f32::INFINITY,
+            PatienceGen::Range(ref gen) => gen.generate(rng),
+            PatienceGen::Exponential { mean } => -mean * (1.0 - rng.next_f32()).ln(),
+        }
+    }
+}
+
+/// Decides whether users keep playing after a game, bad experience shifts the base probabilities
+/// down on the logit scale: per lost game in a row, per 100 points of the real skill difference
+/// of the teams and per 60 ticks spent in the queue
+#[derive(Debug)]
+pub struct RetentionModel {
+    pub continue_prob: f32,
+    /// The probability that a user who stopped playing comes back later
+    pub return_prob: f32,
+    pub return_delay_mean: f32,
+
+    pub loss_streak_weight: f32,
+    pub imbalance_weight: f32,
+    pub wait_weight: f32,
+}
+
+impl RetentionModel {
+    pub fn continue_prob(&self, user: &UserData) -> f32 {
+        self.adjust(self.continue_prob * user.activity, user)
+    }
+
+    pub fn return_prob(&self, user: &UserData) -> f32 {
+        self.adjust(self.return_prob, user)
+    }
+
+    fn adjust(&self, prob: f32, user: &UserData) -> f32 {
+        if prob <= 0.0 || prob >= 1.0 {
+            return prob.clamp(0.0, 1.0);
+        }
+
+        let penalty = self.loss_streak_weight * user.loss_streak as f32
+            + self.imbalance_weight * user.last_imbalance / 100.0
+            + self.wait_weight * user.last_wait as f32 / 60.0;
+
+        let logit = (prob / (1.0 - prob)).ln() - penalty;
+        1.0 / (1.0 + (-logit).exp())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LeaverResolution {
+    /// The team with more leavers loses
+    Forfeit,
+    /// The game result decider judges the players who stayed
+    Decider,
+}
+
+/// Players abandoning games in progress and the consequences they face
+#[derive(Debug)]
+pub struct LeaverPolicy {
+    /// Probability that a player leaves a game before its end
+    pub leave_prob: f32,
+    pub resolution: LeaverResolution,
+    /// Rating a leaver loses on top of the game result
+    pub rating_penalty: f32,
+    /// Amount of games a leaver plays in the low priority queue
+    pub low_priority_games: u32,
+}
+
+impl LeaverPolicy {
+    /// Winning team of a game with leavers, `None` if the game is decided as usual
+    pub fn forfeit(&self, leavers_team1: usize, leavers_team2: usize) -> Option<u32> {
+        if self.resolution != LeaverResolution::Forfeit || leavers_team1 == leavers_team2 {
+            return None;
+        }
+
+        Some(if leavers_team1 > leavers_team2 { 2 } else { 1 })
+    }
+}
+
+/// Changes of the real skill level: learning of new players, decay during inactivity and random drift
+#[derive(Debug)]
+pub struct SkillDynamics {
+    /// Real skill level a new player gains over the learning curve
+    pub learning_gain: f32,
+    /// Amount of games it takes to get half of the learning gain
+    pub learning_games: f32,
+    /// Real skill level lost per 3600 ticks offline
+    pub inactivity_decay: f32,
+    /// Standard deviation of the real skill level change per game
+    pub drift: f32,
+}
 
-        return ((self.max - self.min) * rand) + self.min;
+impl SkillDynamics {
+    pub fn after_game(&self, user: &mut UserData, rng: &mut SimRng) {
+        if self.learning_gain != 0.0 && self.learning_games > 0.0 {
+            let games = user.games_played as f32;
+            let remaining_before = 0.5_f32.powf((games - 1.0) / self.learning_games);
+            let remaining_after = 0.5_f32.powf(games / self.learning_games);
+
+            user.real_skill += self.learning_gain * (remaining_before - remaining_after);
+        }
+
+        if self.drift > 0.0 {
+            user.real_skill += self.drift * standard_normal(rng);
+        }
+    }
+
+    pub fn after_inactivity(&self, user: &mut UserData, ticks: u32) {
+        user.real_skill -= self.inactivity_decay * (ticks as f32) / 3600.0;
+    }
+}
+
+// https://en.wikipedia.org/wiki/Box%E2%80%93Muller_transform
+pub fn standard_normal(rng: &mut SimRng) -> f32 {
+    let u1 = 1.0 - rng.next_f32();
+    let u2 = rng.next_f32();
+
+    (-2.0 * u1.ln()).sqrt() * ((2.0 * PI * u2).cos())
+}
+
+/// Amount of events of a Poisson process with the given mean
+pub fn poisson(lambda: f32, rng: &mut SimRng) -> u32 {
+    if lambda <= 0.0 {
+        return 0;
+    }
+
+    // the normal approximation is precise enough for the big rates and avoids the long product below
+    if lambda > 30.0 {
+        return (lambda + lambda.sqrt() * standard_normal(rng)).round().max(0.0) as u32;
+    }
+
+    // https://en.wikipedia.org/wiki/Poisson_distribution#Generating_Poisson-distributed_random_variables
+    let limit = (-lambda).exp();
+    let mut count = 0;
+    let mut product = rng.next_f32();
+
+    while product > limit {
+        count += 1;
+        product *= rng.next_f32();
+    }
+
+    count
+}
+
+/// Every game tells something about the user, so the imported deviation fades and the K factor gets back to the base one
+fn settle_deviation(user: &mut UserData) {
+    if let Some(deviation) = user.rating_deviation {
+        user.rating_deviation = Some(UNRATED_DEVIATION + (deviation - UNRATED_DEVIATION) * DEVIATION_DECAY);
     }
 }
 
@@ -343,7 +805,7 @@ fn test_skill_empty_queue() {
     let pool = UserPool::new(false);
     let mut queue = Vec::new();
 
-    assert!(algorithm.search(&mut queue, &pool) == AlgorithmResult::None);
+    assert!(algorithm.search(&mut queue, &pool, &mut new_rng(0)) == AlgorithmResult::None);
 }
 
 #[test]
@@ -361,7 +823,7 @@ fn test_skill_small_queue() {
         queue.push(pool.generate(500.0, 500.0))
     }
 
-    assert!(algorithm.search(&mut queue, &pool) == AlgorithmResult::None);
+    assert!(algorithm.search(&mut queue, &pool, &mut new_rng(0)) == AlgorithmResult::None);
 }
 
 #[test]
@@ -381,14 +843,14 @@ fn test_skill_small_prefill() {
         queue.push(pool.generate(500.0, 500.0))
     }
 
-    let result = algorithm.search(&mut queue, &pool);
+    let result = algorithm.search(&mut queue, &pool, &mut new_rng(0));
 
     match result {
         AlgorithmResult::Found(game) => {
             assert!(game.team1.len() == 5);
             assert!(game.team2.len() == 5);
 
-            assert_eq!(10000.0, pool.get_user(game.team1.get(0).unwrap()).get_skill());
+            assert_eq!(10000.0, pool.get_user(game.team1.first().unwrap()).get_skill());
         }
         _ => panic!("Incorrect result")
     }
@@ -409,7 +871,7 @@ fn test_skill_found() {
         queue.push(pool.generate(500.0, 500.0))
     }
 
-    let result = algorithm.search(&mut queue, &pool);
+    let result = algorithm.search(&mut queue, &pool, &mut new_rng(0));
 
     match result {
         AlgorithmResult::Found(game) => {
@@ -442,7 +904,7 @@ fn test_clustered_queue() {
     }
 
     thread_rng().shuffle(&mut queue);
-    let result = algorithm.search(&mut queue, &pool);
+    let result = algorithm.search(&mut queue, &pool, &mut new_rng(0));
 
     println!("{:?}", queue);
     assert!(queue.len() == 15);
@@ -485,7 +947,7 @@ fn test_skill_level_sum() {
     }
 
     thread_rng().shuffle(&mut queue);
-    let result = algorithm.search(&mut queue, &pool);
+    let result = algorithm.search(&mut queue, &pool, &mut new_rng(0));
 
     println!("Queue: {:?}", queue);
     assert!(queue.len() == 9);
@@ -496,14 +958,32 @@ fn test_skill_level_sum() {
             assert!(game.team1.len() == 5);
             assert!(game.team2.len() == 5);
 
-            let team1_sum = game.team1.iter().fold(0.0, |sum, id| sum + pool.get_user(&id).get_skill());
-            let team2_sum = game.team2.iter().fold(0.0, |sum, id| sum + pool.get_user(&id).get_skill());
+            let team1_sum = game.team1.iter().fold(0.0, |sum, id| sum + pool.get_user(id).get_skill());
+            let team2_sum = game.team2.iter().fold(0.0, |sum, id| sum + pool.get_user(id).get_skill());
             assert!((team1_sum - team2_sum).abs() <= 100.0);
         }
         _ => panic!("Incorrect result")
     }
 }
 
+#[test]
+fn requeue_keeps_join_order() {
+    let mut pool = UserPool::new(false);
+    for join_time in &[10, 20, 30, 40] {
+        let id = pool.generate(1500.0, 1500.0);
+        pool.get_user_mut(&id).set_join_time(*join_time);
+    }
+
+    let mut queue = vec![0, 2];
+    requeue(&mut queue, &pool, 3, true);
+    requeue(&mut queue, &pool, 1, true);
+    assert_eq!(queue, vec![0, 1, 2, 3]);
+
+    let mut queue = vec![2, 3];
+    requeue(&mut queue, &pool, 0, false);
+    assert_eq!(queue, vec![2, 3, 0]);
+}
+
 #[test]
 fn rating_update() {
     let mut pool = UserPool::new(false);
@@ -513,7 +993,7 @@ fn rating_update() {
 
     let game = Game::new(vec!(user1), vec!(user2));
 
-    game.process(&pool, 1);
+    game.process(&mut pool, 1);
 
     assert!((pool.get_user(&user1).get_skill() - 2403.0).abs() < 0.1);
     assert!((pool.get_user(&user2).get_skill() - 1997.0).abs() < 0.1);
@@ -528,7 +1008,7 @@ fn rating_update2() {
 
     let game = Game::new(vec!(user1), vec!(user2));
 
-    game.process(&pool, 2);
+    game.process(&mut pool, 2);
 
     assert!((pool.get_user(&user1).get_skill() - 2371.0).abs() < 0.1);
     assert!((pool.get_user(&user2).get_skill() - 2029.0).abs() < 0.1);
@@ -548,7 +1028,7 @@ fn team_rating_update() {
 
     let game = Game::new(vec!(user1, user2), vec!(user3, user4));
 
-    game.process(&pool, 2);
+    game.process(&mut pool, 2);
 
     println!("{:?}", pool);
 
@@ -559,3 +1039,208 @@ fn team_rating_update() {
 }
 
 
+
+#[test]
+fn poisson_mean() {
+    let mut rng = new_rng(42);
+
+    for lambda in &[0.05, 3.0, 100.0] {
+        let samples = 20000;
+        let sum: u32 = (0..samples).map(|_| poisson(*lambda, &mut rng)).sum();
+        let mean = sum as f32 / samples as f32;
+
+        assert!((mean - lambda).abs() < lambda * 0.05 + 0.01, "lambda: {}, mean: {}", lambda, mean);
+    }
+}
+
+#[test]
+fn patience_exponential_mean() {
+    let gen = PatienceGen::Exponential { mean: 300.0 };
+    let mut rng = new_rng(7);
+
+    let samples = 20000;
+    let sum: f32 = (0..samples).map(|_| gen.generate(&mut rng)).sum();
+
+    assert!((sum / samples as f32 - 300.0).abs() < 15.0);
+    assert_eq!(PatienceGen::Unlimited.generate(&mut rng), f32::INFINITY);
+}
+
+#[test]
+fn retention_penalty() {
+    let mut retention = RetentionModel {
+        continue_prob: 0.5,
+        return_prob: 0.0,
+        return_delay_mean: 0.0,
+        loss_streak_weight: 1.0,
+        imbalance_weight: 0.0,
+        wait_weight: 0.0,
+    };
+
+    let mut pool = UserPool::new(false);
+    let id = pool.generate(1500.0, 1500.0);
+
+    assert!((retention.continue_prob(pool.get_user(&id)) - 0.5).abs() < 0.001);
+    assert_eq!(retention.return_prob(pool.get_user(&id)), 0.0);
+
+    pool.get_user_mut(&id).loss_streak = 2;
+    // logit(0.5) - 2
+    assert!((retention.continue_prob(pool.get_user(&id)) - 0.1192).abs() < 0.001);
+
+    retention.continue_prob = 1.0;
+    assert_eq!(retention.continue_prob(pool.get_user(&id)), 1.0);
+}
+
+#[test]
+fn learning_curve() {
+    let dynamics = SkillDynamics {
+        learning_gain: 400.0,
+        learning_games: 10.0,
+        inactivity_decay: 36.0,
+        drift: 0.0,
+    };
+
+    let mut pool = UserPool::new(false);
+    let id = pool.generate(1500.0, 1000.0);
+    let mut rng = new_rng(0);
+
+    for _ in 0..10 {
+        let user = pool.get_user_mut(&id);
+        user.add_game_played();
+        dynamics.after_game(user, &mut rng);
+    }
+    assert!((pool.get_user(&id).real_skill - 1200.0).abs() < 0.1);
+
+    dynamics.after_inactivity(pool.get_user_mut(&id), 36000);
+    assert!((pool.get_user(&id).real_skill - 840.0).abs() < 0.1);
+}
+
+#[test]
+fn truncated_normal() {
+    let gen = RandomRangeGen::new(1000.0, 2000.0, DistributionType::Normal { mean: 1500.0, sigma: 500.0 });
+    let mut rng = new_rng(11);
+
+    let values: Vec<f32> = (0..10000).map(|_| gen.generate(&mut rng)).collect();
+    assert!(values.iter().all(|v| *v >= 1000.0 && *v <= 2000.0));
+
+    // rejection keeps the tails thin instead of piling the mass on the bounds
+    let at_bounds = values.iter().filter(|v| **v < 1010.0 || **v > 1990.0).count();
+    assert!(at_bounds < 300, "{}", at_bounds);
+
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    assert!((mean - 1500.0).abs() < 15.0);
+}
+
+#[test]
+fn lognormal_skewed() {
+    let gen = RandomRangeGen::new(800.0, 10000.0, DistributionType::LogNormal { mean: 1500.0, sigma: 400.0 });
+    let mut rng = new_rng(5);
+
+    let mut values: Vec<f32> = (0..10000).map(|_| gen.generate(&mut rng)).collect();
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    let median = values[values.len() / 2];
+
+    assert!((mean - 1500.0).abs() < 20.0);
+    assert!(median < mean);
+}
+
+#[test]
+fn empirical_histogram() {
+    let buckets = parse_histogram("# mmr,players\n1000,0\n1200,3\n1100,1\n");
+    assert_eq!(buckets, vec![(1000.0, 0.0), (1100.0, 1.0), (1200.0, 3.0)]);
+
+    let gen = RandomRangeGen::new(0.0, 5000.0, DistributionType::Empirical(buckets));
+    let mut rng = new_rng(9);
+
+    let values: Vec<f32> = (0..4000).map(|_| gen.generate(&mut rng)).collect();
+    assert!(values.iter().all(|v| *v >= 1100.0 && *v < 1300.0));
+
+    let top = values.iter().filter(|v| **v >= 1200.0).count();
+    assert!((top as i32 - 3000).abs() < 150);
+}
+
+#[test]
+fn rating_deviation_scales_update() {
+    let mut pool = UserPool::new(false);
+    for _ in 0..4 {
+        pool.generate(1500.0, 1500.0);
+    }
+    pool.get_user_mut(&0).rating_deviation = Some(700.0);
+    pool.get_user_mut(&2).rating_deviation = Some(175.0);
+
+    let game = Game::new(vec![0, 1], vec![2, 3]);
+    game.process(&mut pool, 1);
+
+    assert_eq!(pool.get_user(&0).get_skill(), 1532.0);
+    assert_eq!(pool.get_user(&1).get_skill(), 1516.0);
+    assert_eq!(pool.get_user(&2).get_skill(), 1492.0);
+    assert_eq!(pool.get_user(&3).get_skill(), 1484.0);
+
+    assert_eq!(pool.get_user(&0).rating_deviation, Some(665.0));
+    assert_eq!(pool.get_user(&2).rating_deviation, Some(192.5));
+    assert_eq!(pool.get_user(&1).rating_deviation, None);
+}
+
+#[test]
+fn rating_deviation_settles() {
+    let mut pool = UserPool::new(false);
+    for _ in 0..2 {
+        pool.generate(1500.0, 1500.0);
+    }
+    pool.get_user_mut(&0).rating_deviation = Some(700.0);
+
+    for _ in 0..100 {
+        Game::new(vec![0], vec![1]).process(&mut pool, 1);
+    }
+
+    let deviation = pool.get_user(&0).rating_deviation.unwrap();
+    assert!((deviation - UNRATED_DEVIATION).abs() < 0.01);
+    assert!((k_factor(pool.get_user(&0), 32.0) - 32.0).abs() < 0.01);
+}
+
+#[test]
+fn leaver_forfeit() {
+    let mut policy = LeaverPolicy { leave_prob: 0.1, resolution: LeaverResolution::Forfeit, rating_penalty: 0.0, low_priority_games: 0 };
+
+    assert_eq!(policy.forfeit(0, 0), None);
+    assert_eq!(policy.forfeit(2, 2), None);
+    assert_eq!(policy.forfeit(1, 0), Some(2));
+    assert_eq!(policy.forfeit(1, 2), Some(1));
+
+    policy.resolution = LeaverResolution::Decider;
+    assert_eq!(policy.forfeit(1, 0), None);
+}
+
+#[test]
+fn backfill_skill_proximity() {
+    let mut pool = UserPool::new(false);
+    pool.generate(1000.0, 1000.0);
+    pool.generate(1700.0, 1000.0);
+    pool.generate(1450.0, 1000.0);
+    let policy = SkillProximityBackfill { max_skill_delta: 200.0, min_remaining: 0.25 };
+
+    let slot = BackfillSlot { skill: 1500.0, remaining: 100, length: 200 };
+    assert_eq!(policy.pick(&[0, 1, 2], &pool, &slot), Some(2));
+    assert_eq!(policy.pick(&[0, 1], &pool, &slot), Some(1));
+    assert_eq!(policy.pick(&[0], &pool, &slot), None);
+
+    let late = BackfillSlot { skill: 1500.0, remaining: 40, length: 200 };
+    assert_eq!(policy.pick(&[0, 1, 2], &pool, &late), None);
+}
+
+#[test]
+fn win_probability_elo() {
+    assert_eq!(win_probability(1500.0, 1500.0), 0.5);
+    assert!((win_probability(1900.0, 1500.0) - 0.9091).abs() < 0.001);
+    assert!((win_probability(1500.0, 1900.0) + win_probability(1900.0, 1500.0) - 1.0).abs() < 0.0001);
+
+    let mut pool = UserPool::new(false);
+    pool.generate(1900.0, 1000.0);
+    pool.generate(1500.0, 1000.0);
+    let game = Game::new(vec![0], vec![1]);
+
+    let (deltas1, deltas2) = game.process(&mut pool, 2);
+    assert!((deltas1[0] + 29.09).abs() < 0.01);
+    assert!((deltas2[0] - 29.09).abs() < 0.01);
+}
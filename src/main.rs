@@ -1,108 +1,665 @@
 #![allow(dead_code)]
 
-#[macro_use] extern crate rand;
+extern crate rand;
 extern crate clap;
+extern crate flate2;
 
+mod arrivals;
+mod data;
 mod entities;
+mod replay;
+mod report;
+mod stats;
 
+use arrivals::*;
 use entities::*;
+use replay::*;
+use report::*;
+use stats::{kendall, percentile, spearman, tiers, top_n_precision, Histogram, PredictionStats, RunningSummary};
 
 use rand::{thread_rng, Rng};
+use std::borrow::Cow;
 use std::collections::HashMap;
-use clap::{App, Arg};
+use std::sync::Mutex;
+use std::thread;
+use clap::{App, Arg, ArgMatches};
 
 fn main() {
-    let params = App::new("MatchMaking modeling")
+    let params = app().get_matches();
+
+    let ticks = params.value_of("time").unwrap().parse::<u32>().unwrap();
+    let search_delay = params.value_of("search_delay").unwrap().parse::<u32>().unwrap();
+    let replications = params.value_of("replications").unwrap().parse::<u64>().unwrap().max(1);
+
+    let name = params.value_of("name")
+        .map(String::from)
+        .unwrap_or(String::from("report_") + &thread_rng().next_u32().to_string());
+
+    let seed = params.value_of("seed")
+        .map(|str| str.parse::<u64>().unwrap())
+        .unwrap_or_else(|| thread_rng().next_u64());
+
+    let jobs = params.value_of("jobs")
+        .map(|str| str.parse::<usize>().unwrap())
+        .unwrap_or_else(|| thread::available_parallelism().map(|cores| cores.get()).unwrap_or(1));
+
+    let arguments = command_line_arguments(&params);
+    let options = ReportOptions {
+        compress: params.is_present("compress"),
+    };
+
+    if params.value_of("mode").unwrap() == "replay" {
+        let events = replay_matches(&params, &name);
+        save_results(&mut ReportWriter::create(&name, &arguments, &options), events);
+        return;
+    }
+
+    let algorithms: Vec<&str> = match params.value_of("mode").unwrap() {
+        "compare" => params.values_of("compare_algs").unwrap().collect(),
+        _ => vec![params.value_of("algorithm").unwrap()],
+    };
+
+    // every algorithm is simulated with the same seeds, so all of them observe the same traffic
+    let trace = params.value_of("arrivals").map(TraceArrivals::load);
+    let population = params.value_of("population").map(Population::load);
+
+    let report_names: Vec<String> = algorithms.iter()
+        .map(|algorithm| if algorithms.len() == 1 { name.clone() } else { format!("{}_{}", name, algorithm) })
+        .collect();
+
+    let mut models = Vec::new();
+    for (algorithm, report_name) in algorithms.iter().zip(&report_names) {
+        if replications == 1 {
+            models.push(build_model(&params, report_name.clone(), seed, algorithm, trace.clone(), population.clone()));
+            continue;
+        }
+
+        for index in 0..replications {
+            models.push(build_model(&params, format!("{}_{}", report_name, index), derive_seed(seed, index), algorithm, trace.clone(), population.clone()));
+        }
+    }
+
+    let mut reports = Vec::new();
+
+    if replications == 1 {
+        // single runs stream their events into the reports
+        let sinks = report_names.iter()
+            .map(|report_name| Summarizing::new(ReportWriter::create(report_name, &arguments, &options)))
+            .collect();
+
+        for (algorithm, sink) in algorithms.iter().zip(run_models(models, sinks, ticks, search_delay, jobs)) {
+            reports.push((algorithm.to_string(), sink.summary()));
+        }
+    } else {
+        // replications are aggregated per tick and stat as the runs go, the report is written once all of them finish
+        let aggregates: Vec<Mutex<Replications>> = algorithms.iter().map(|_| Mutex::new(Replications::default())).collect();
+        let sinks = aggregates.iter()
+            .flat_map(|aggregate| (0..replications).map(move |index| ReplicationRun::new(index == 0, aggregate)))
+            .collect();
+        run_models(models, sinks, ticks, search_delay, jobs);
+
+        for ((algorithm, report_name), aggregate) in algorithms.iter().zip(&report_names).zip(aggregates) {
+            let mut sink = Summarizing::new(ReportWriter::create(report_name, &arguments, &options));
+            save_results(&mut sink, aggregate.into_inner().unwrap().events(report_name, seed, replications));
+            reports.push((algorithm.to_string(), sink.summary()));
+        }
+    }
+
+    if algorithms.len() > 1 {
+        save_comparison(&name, &reports);
+    }
+}
+
+/// Runs independent models on up to `jobs` threads, every model writes into its own sink.
+/// The finished sinks keep the order of the models.
+fn run_models<S: EventSink>(models: Vec<Model>, sinks: Vec<S>, ticks: u32, search_delay: u32, jobs: usize) -> Vec<S> {
+    let count = models.len();
+    let tasks = Mutex::new(models.into_iter().zip(sinks).enumerate());
+    let logs: Mutex<Vec<Option<S>>> = Mutex::new((0..count).map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(count).max(1) {
+            scope.spawn(|| loop {
+                let task = tasks.lock().unwrap().next();
+                match task {
+                    Some((index, (mut model, mut sink))) => {
+                        model.run(ticks, search_delay, &mut sink);
+                        sink.finish();
+                        if model.user_history {
+                            save_user_history(&model.name, &model.user_pool);
+                        }
+                        if let Some(ref mut log) = model.match_log {
+                            log.finish();
+                            println!("Match log saved into: {}", log.path());
+                        }
+                        if let Some(ref mut log) = model.rating_log {
+                            log.finish();
+                            println!("Rating trajectories saved into: {}", log.path());
+                        }
+                        logs.lock().unwrap()[index] = Some(sink);
+                    }
+                    None => break,
+                }
+            });
+        }
+    });
+
+    logs.into_inner().unwrap().into_iter().map(Option::unwrap).collect()
+}
+
+/// Runs only the rating system over the games of a match log and reports how well it predicted the results
+fn replay_matches(params: &ArgMatches, name: &str) -> Vec<Event> {
+    let path = params.value_of("matches").unwrap();
+    let matches = load_match_log(path);
+    let default_skill = params.value_of("skill").unwrap().parse::<f32>().unwrap();
+
+    let mut user_pool = UserPool::new(false);
+    user_pool.k_factor = params.value_of("k_factor").unwrap().parse::<f32>().unwrap();
+    let real_skills_known = matches.iter().all(|record| record.team1_real_skills.is_some());
+
+    let mut events = vec![
+        Event::StrParam("name", name.to_owned()),
+        Event::StrParam("mode", String::from("replay")),
+        Event::StrParam("matches", path.to_owned()),
+        Event::StrParam("k_factor", user_pool.k_factor.to_string()),
+    ];
+
+    println!("Replaying: {}, games: {}, K factor: {}", path, matches.len(), user_pool.k_factor);
+
+    let mut users: HashMap<u64, UserId> = HashMap::new();
+    let mut predictions = PredictionStats::default();
+    let mut rating_log = create_rating_log(name);
+
+    for (index, record) in matches.iter().enumerate() {
+        let mut team = |ids: &[u64], ratings: &Option<Vec<f32>>, real_skills: &Option<Vec<f32>>| -> Vec<UserId> {
+            ids.iter().enumerate()
+                .map(|(position, key)| {
+                    let real_skill = real_skills.as_ref().map(|skills| skills[position]);
+                    let id = *users.entry(*key).or_insert_with(|| {
+                        let rating = ratings.as_ref().map(|ratings| ratings[position]).unwrap_or(default_skill);
+                        let id = user_pool.generate(rating, real_skill.unwrap_or(0.0));
+                        user_pool.get_user_mut(&id).created_tick = record.tick;
+                        id
+                    });
+
+                    // the real skill level of a simulated player changes over time
+                    if let Some(real_skill) = real_skill {
+                        user_pool.get_user_mut(&id).real_skill = real_skill;
+                    }
+                    id
+                })
+                .collect()
+        };
+
+        let game = Game::new(team(&record.team1, &record.team1_ratings, &record.team1_real_skills),
+                             team(&record.team2, &record.team2_ratings, &record.team2_real_skills));
+
+        predictions.add(game.win_probability(&user_pool), record.winner == 1);
+        game.process(&mut user_pool, record.winner);
+
+        for (team, won) in &[(&game.team1, record.winner == 1), (&game.team2, record.winner == 2)] {
+            for id in team.iter() {
+                let user = user_pool.get_user_mut(id);
+                user.add_game_played();
+                rating_log.line(format_args!("{},{},{}", record.tick, id, user.get_skill()));
+                if *won {
+                    user.history.wins += 1;
+                } else {
+                    user.history.losses += 1;
+                }
+            }
+        }
+
+        // the metrics are reported once all the games of the tick are replayed
+        if matches.get(index + 1).map(|next| next.tick) != Some(record.tick) {
+            push_prediction_metrics(record.tick, &predictions, &mut events);
+            if real_skills_known {
+                events.push(Event::TimedFloat(record.tick, Cow::Borrowed("avg_skill_error"), user_pool.get_avg_skill_error()));
+            }
+        }
+    }
+
+    push_prediction_summary(&predictions, &mut events);
+
+    println!("Accuracy: {}, log loss: {}, Brier score: {}", predictions.accuracy(), predictions.log_loss(), predictions.brier());
+
+    save_user_history(name, &user_pool);
+    rating_log.finish();
+    println!("Rating trajectories saved into: {}", rating_log.path());
+    events
+}
+
+fn push_prediction_metrics(tick: u32, predictions: &PredictionStats, events: &mut Vec<Event>) {
+    events.push(Event::TimedFloat(tick, Cow::Borrowed("accuracy"), predictions.accuracy()));
+    events.push(Event::TimedFloat(tick, Cow::Borrowed("log_loss"), predictions.log_loss()));
+    events.push(Event::TimedFloat(tick, Cow::Borrowed("brier_score"), predictions.brier()));
+}
+
+/// Totals of the predictions with the calibration table, a row per tenth of the predicted probability
+fn push_prediction_summary(predictions: &PredictionStats, events: &mut Vec<Event>) {
+    events.push(Event::Float(Cow::Borrowed("games"), predictions.count as f32));
+    events.push(Event::Float(Cow::Borrowed("accuracy"), predictions.accuracy()));
+    events.push(Event::Float(Cow::Borrowed("log_loss"), predictions.log_loss()));
+    events.push(Event::Float(Cow::Borrowed("brier_score"), predictions.brier()));
+    for (bucket, predicted, observed, count) in predictions.calibration() {
+        events.push(Event::Float(Cow::Owned(format!("calibration_{}_predicted", bucket)), predicted));
+        events.push(Event::Float(Cow::Owned(format!("calibration_{}_observed", bucket)), observed));
+        events.push(Event::Float(Cow::Owned(format!("calibration_{}_games", bucket)), count as f32));
+    }
+}
+
+/// Settings of a command line argument, applied to the argument created by its name
+type ArgBuilder = fn(Arg<'static, 'static>) -> Arg<'static, 'static>;
+
+/// All the command line arguments by their name, `app` registers them in this order
+const ARGUMENTS: &[(&str, ArgBuilder)] = &[
+    ("time", |arg| arg
+        .short("t")
+        .help("A period of time to simulate in seconds")
+        .default_value("3600")),
+
+    ("users_at_start", |arg| arg
+        .short("u")
+        .help("Amount of users to be generated")
+        .default_value("1000")),
+    ("users_to_gen", |arg| arg
+        .short("g")
+        .help("Amount of users to be generated")
+        .default_value("500")),
+
+    ("arrivals", |arg| arg
+        .long("arrivals")
+        .takes_value(true)
+        .help("Trace file with `tick,user_id,real_skill[,rating[,party]]` lines replayed instead of generated users")),
+    ("population", |arg| arg
+        .long("population")
+        .takes_value(true)
+        .conflicts_with("arrivals")
+        .help("File with `real_skill,rating[,rating_deviation[,activity]]` lines of existing players entering instead of users_at_start")),
+
+    ("arrival_profile", |arg| arg
+        .long("arrival_profile")
+        .help("Shape of the arrival rate of generated users over the day")
+        .possible_values(&["constant", "diurnal", "schedule"])
+        .default_value("constant")),
+    ("profile_period", |arg| arg
+        .long("profile_period")
+        .takes_value(true)
+        .help("Period of the arrival profile in ticks")
+        .default_value("86400")),
+    ("diurnal_amplitude", |arg| arg
+        .long("diurnal_amplitude")
+        .takes_value(true)
+        .help("Relative amplitude of the diurnal arrival rate")
+        .default_value("0.5")),
+    ("diurnal_peak", |arg| arg
+        .long("diurnal_peak")
+        .takes_value(true)
+        .help("Tick of the period with the highest diurnal arrival rate")
+        .default_value("72000")),
+    ("profile_file", |arg| arg
+        .long("profile_file")
+        .takes_value(true)
+        .required_if("arrival_profile", "schedule")
+        .help("File with `tick,multiplier` lines of the schedule arrival profile")),
+    ("burst", |arg| arg
+        .long("burst")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Arrival rate burst as `start:duration:factor`, e.g. a patch day, can be repeated")),
+
+    ("smurf_share", |arg| arg
+        .long("smurf_share")
+        .takes_value(true)
+        .help("Share of the generated users who are smurfs: experienced players starting at the default skill level")
+        .default_value("0.0")),
+    ("smurf_offset", |arg| arg
+        .long("smurf_offset")
+        .takes_value(true)
+        .help("Real skill level smurfs have above the generated one")
+        .default_value("500")),
+    ("veteran_share", |arg| arg
+        .long("veteran_share")
+        .takes_value(true)
+        .help("Share of the generated users who are veterans returning with a stale rating")
+        .default_value("0.0")),
+    ("veteran_offset", |arg| arg
+        .long("veteran_offset")
+        .takes_value(true)
+        .help("Difference between the stale rating of veterans and their real skill level")
+        .default_value("300")),
+    ("convergence_threshold", |arg| arg
+        .long("convergence_threshold")
+        .takes_value(true)
+        .help("Skill level error below which the rating of a user is considered converged")
+        .default_value("100")),
+
+    ("name", |arg| arg
+        .short("n")
+        .takes_value(true)
+        .help("Name of the simulation")),
+    ("search_delay", |arg| arg
+        .short("d")
+        .long("search_delay")
+        .takes_value(true)
+        .help("Delay between searches in ticks")
+        .default_value("1")),
+    ("skill", |arg| arg
+        .short("s")
+        .help("Default skill level assigned to the user")
+        .default_value("1500")),
+    ("real_skill_max", |arg| arg
+        .long("rmax")
+        .help("Maximum value of the skill level")
+        .default_value("2200")),
+    ("real_skill_min", |arg| arg
+        .long("rmin")
+        .help("Minimum value of the skill level")
+        .default_value("800")),
+
+    ("skill_dist", |arg| arg
+        .long("skill_dist")
+        .help("Distribution of the real skill level, truncated to [rmin, rmax]")
+        .possible_values(&["uniform", "normal", "lognormal", "bimodal", "empirical"])
+        .default_value("uniform")),
+    ("skill_mean", |arg| arg
+        .long("skill_mean")
+        .takes_value(true)
+        .help("Mean real skill level of the normal and log-normal distributions, of the first mode of the bimodal one")
+        .default_value("1500")),
+    ("skill_sigma", |arg| arg
+        .long("skill_sigma")
+        .takes_value(true)
+        .help("Standard deviation of the real skill level, of the first mode of the bimodal distribution")
+        .default_value("300")),
+    ("skill_mean2", |arg| arg
+        .long("skill_mean2")
+        .takes_value(true)
+        .help("Mean real skill level of the second mode of the bimodal distribution")
+        .default_value("1900")),
+    ("skill_sigma2", |arg| arg
+        .long("skill_sigma2")
+        .takes_value(true)
+        .help("Standard deviation of the second mode of the bimodal distribution")
+        .default_value("150")),
+    ("skill_mix", |arg| arg
+        .long("skill_mix")
+        .takes_value(true)
+        .help("Share of the users belonging to the second mode of the bimodal distribution")
+        .default_value("0.3")),
+    ("skill_histogram", |arg| arg
+        .long("skill_histogram")
+        .takes_value(true)
+        .required_if("skill_dist", "empirical")
+        .help("File with `bucket start,weight` lines of the empirical real skill level distribution")),
+
+    ("max_game_length", |arg| arg
+        .long("max_game_length")
+        .help("The amount of time before user reenter queue")
+        .default_value("300")),
+    ("continuous_play_prob", |arg| arg
+        .long("continuous_play_prob")
+        .help("The probability that after a game user will join the queue")
+        .default_value("0.0")),
+
+    ("patience", |arg| arg
+        .long("patience")
+        .help("Distribution of the time users wait in the queue before giving up")
+        .possible_values(&["unlimited", "uniform", "normal", "exponential"])
+        .default_value("unlimited")),
+    ("patience_min", |arg| arg
+        .long("patience_min")
+        .takes_value(true)
+        .help("Minimum patience of the uniform and normal distributions")
+        .default_value("60")),
+    ("patience_max", |arg| arg
+        .long("patience_max")
+        .takes_value(true)
+        .help("Maximum patience of the uniform and normal distributions")
+        .default_value("600")),
+    ("patience_mean", |arg| arg
+        .long("patience_mean")
+        .takes_value(true)
+        .help("Mean patience of the exponential distribution")
+        .default_value("300")),
+    ("rejoin_prob", |arg| arg
+        .long("rejoin_prob")
+        .takes_value(true)
+        .help("The probability that a user who gave up waiting joins the queue again")
+        .default_value("0.0")),
+    ("rejoin_delay", |arg| arg
+        .long("rejoin_delay")
+        .takes_value(true)
+        .help("The amount of time before a user who gave up waiting joins the queue again")
+        .default_value("300")),
+
+    ("return_prob", |arg| arg
+        .long("return_prob")
+        .takes_value(true)
+        .help("The probability that a user who stopped playing after a game comes back later")
+        .default_value("0.0")),
+    ("return_delay", |arg| arg
+        .long("return_delay")
+        .takes_value(true)
+        .help("Mean amount of time before a user who stopped playing comes back")
+        .default_value("3600")),
+    ("loss_streak_weight", |arg| arg
+        .long("loss_streak_weight")
+        .takes_value(true)
+        .help("Decrease of the continue and return probabilities (logit) per lost game in a row")
+        .default_value("0.0")),
+    ("imbalance_weight", |arg| arg
+        .long("imbalance_weight")
+        .takes_value(true)
+        .help("Decrease of the continue and return probabilities (logit) per 100 points of the teams real skill difference")
+        .default_value("0.0")),
+    ("wait_weight", |arg| arg
+        .long("wait_weight")
+        .takes_value(true)
+        .help("Decrease of the continue and return probabilities (logit) per 60 ticks spent in the queue")
+        .default_value("0.0")),
+    ("session_games", |arg| arg
+        .long("session_games")
+        .takes_value(true)
+        .help("Mean amount of games users play before logging out, every user has own session length, 0 disables sessions")
+        .default_value("0")),
+    ("leave_prob", |arg| arg
+        .long("leave_prob")
+        .takes_value(true)
+        .help("Probability that a player leaves a game before its end")
+        .default_value("0.0")),
+    ("leaver_resolution", |arg| arg
+        .long("leaver_resolution")
+        .help("How a game with leavers is resolved: the short-handed team loses or the decider judges the remaining players")
+        .possible_values(&["forfeit", "decider"])
+        .default_value("forfeit")),
+    ("leaver_penalty", |arg| arg
+        .long("leaver_penalty")
+        .takes_value(true)
+        .help("Rating a leaver loses on top of the game result")
+        .default_value("0.0")),
+    ("low_priority_games", |arg| arg
+        .long("low_priority_games")
+        .takes_value(true)
+        .help("Amount of games a leaver has to play in the low priority queue")
+        .default_value("0")),
+    ("backfill", |arg| arg
+        .long("backfill")
+        .help("Leavers are replaced with queued users while the game is in progress")),
+    ("backfill_skill_delta", |arg| arg
+        .long("backfill_skill_delta")
+        .takes_value(true)
+        .help("Maximum skill level difference between a leaver and the replacement")
+        .default_value("200")),
+    ("backfill_min_remaining", |arg| arg
+        .long("backfill_min_remaining")
+        .takes_value(true)
+        .help("Share of the game that must be left for a leaver to be replaced")
+        .default_value("0.25")),
+    ("accept_prob", |arg| arg
+        .long("accept_prob")
+        .takes_value(true)
+        .help("Probability that a player accepts the ready check of a found game, a single decline dissolves the game")
+        .default_value("1.0")),
+    ("keep_queue_position", |arg| arg
+        .long("keep_queue_position")
+        .help("Players who accepted a failed ready check keep their place in the queue instead of going to its end")),
+    ("decline_ban", |arg| arg
+        .long("decline_ban")
+        .takes_value(true)
+        .help("Ticks a player who declined a ready check stays out of the queue")
+        .default_value("1")),
+    ("prediction_window", |arg| arg
+        .long("prediction_window")
+        .takes_value(true)
+        .help("Window in ticks of the accuracy, log loss and Brier score of the predicted game results")
+        .default_value("600")),
+    ("rank_interval", |arg| arg
+        .long("rank_interval")
+        .takes_value(true)
+        .help("Ticks between the rank correlations of the ratings and the real skill levels of the active users")
+        .default_value("600")),
+    ("top_n", |arg| arg
+        .long("top_n")
+        .takes_value(true)
+        .help("Size of the leaderboard of the top-N precision")
+        .default_value("100")),
+    ("skill_tiers", |arg| arg
+        .long("skill_tiers")
+        .takes_value(true)
+        .help("Amount of real skill percentile tiers of the fairness report")
+        .default_value("5")),
+    ("sample_interval", |arg| arg
+        .long("sample_interval")
+        .takes_value(true)
+        .help("Ticks between the snapshots of the per-tick stats, windowed and per-game values are always written")
+        .default_value("1")),
+    ("compress", |arg| arg
+        .long("compress")
+        .help("Writes gzip compressed reports, reports/<name>.csv.gz")),
+    ("wait_window", |arg| arg
+        .long("wait_window")
+        .takes_value(true)
+        .help("Window in ticks of the completed wait time percentiles")
+        .default_value("600")),
+    ("wait_bucket", |arg| arg
+        .long("wait_bucket")
+        .takes_value(true)
+        .help("Bucket width in ticks of the wait time histogram reported at the end of the run")
+        .default_value("10")),
+    ("user_history", |arg| arg
+        .long("user_history")
+        .help("Exports games, wins, losses and waits of every user into reports/<name>_users.csv and the rating trajectories into reports/<name>_ratings.csv")),
+    ("match_log", |arg| arg
+        .long("match_log")
+        .help("Writes every game with its teams, ratings, real skill levels, prediction, winner and rating deltas into reports/<name>_matches.csv")),
+    ("cohort_length", |arg| arg
+        .long("cohort_length")
+        .takes_value(true)
+        .help("Users created within this amount of ticks form a cohort of the retention report")
+        .default_value("3600")),
+
+    ("learning_gain", |arg| arg
+        .long("learning_gain")
+        .takes_value(true)
+        .help("Real skill level a new player gains over the learning curve")
+        .default_value("0.0")),
+    ("learning_games", |arg| arg
+        .long("learning_games")
+        .takes_value(true)
+        .help("Amount of games it takes to get half of the learning gain")
+        .default_value("20")),
+    ("inactivity_decay", |arg| arg
+        .long("inactivity_decay")
+        .takes_value(true)
+        .help("Real skill level lost per 3600 ticks offline")
+        .default_value("0.0")),
+    ("skill_drift", |arg| arg
+        .long("skill_drift")
+        .takes_value(true)
+        .help("Standard deviation of the real skill level change per game")
+        .default_value("0.0")),
+
+    ("use_real_skill", |arg| arg
+        .long("use_real_skill")
+        .help("Always use real skill level as skill level of the user")),
+
+    ("algorithm", |arg| arg
+        .short("a")
+        .long("alg")
+        .help("Algorithm type")
+        .possible_values(&["fifo", "rnd", "skill"])
+        .default_value("rnd")),
+    ("mode", |arg| arg
+        .long("mode")
+        .help("Simulate a single algorithm, compare several algorithms on identical traffic or replay a match log with the rating system only")
+        .possible_values(&["simulate", "compare", "replay"])
+        .default_value("simulate")),
+    ("matches", |arg| arg
+        .long("matches")
+        .takes_value(true)
+        .required_if("mode", "replay")
+        .help("Match log replayed in the replay mode, e.g. reports/<name>_matches.csv of a previous simulation")),
+    ("k_factor", |arg| arg
+        .long("k_factor")
+        .takes_value(true)
+        .help("Elo K factor of the rating updates")
+        .default_value("32")),
+    ("compare_algs", |arg| arg
+        .long("compare_algs")
+        .takes_value(true)
+        .use_delimiter(true)
+        .help("Algorithms to be compared in the compare mode")
+        .possible_values(&["fifo", "rnd", "skill"])
+        .default_value("fifo,rnd,skill")),
+    ("team_size", |arg| arg
+        .long("team_size")
+        .takes_value(true)
+        .help("The size of the team")
+        .default_value("5")),
+    ("queue_factor", |arg| arg
+        .long("queue_factor")
+        .takes_value(true)
+        .help("Queue overloading factor")
+        .default_value("1.0")),
+    ("prefill_factor", |arg| arg
+        .long("prefill_factor")
+        .takes_value(true)
+        .help("Amount of users to be added to the team on the first run of the search algorithm")
+        .default_value("0.0")),
+
+    ("seed", |arg| arg
+        .long("seed")
+        .takes_value(true)
+        .help("Seed of the random generator, random if not set")),
+    ("replications", |arg| arg
+        .long("replications")
+        .takes_value(true)
+        .help("Amount of runs of the same scenario with derived seeds, aggregated into mean and 95% confidence band")
+        .default_value("1")),
+    ("jobs", |arg| arg
+        .short("j")
+        .long("jobs")
+        .takes_value(true)
+        .help("Maximum amount of runs simulated in parallel, the amount of cores if not set")),
+];
+
+/// Command line of the simulation
+fn app() -> App<'static, 'static> {
+    let app = App::new("MatchMaking modeling")
         .version("1.0")
         .author("botanick333@gmail.com")
-        .about("A humble attempt to simulate typical matchmaking algorithms")
-
-        .arg(Arg::with_name("time")
-            .short("t")
-            .help("A period of time to simulate in seconds")
-            .default_value("3600")
-        )
-
-        .arg(Arg::with_name("users_at_start")
-            .short("u")
-            .help("Amount of users to be generated")
-            .default_value("1000")
-        )
-        .arg(Arg::with_name("users_to_gen")
-            .short("g")
-            .help("Amount of users to be generated")
-            .default_value("500")
-        )
-
-        .arg(Arg::with_name("name")
-            .short("n")
-            .takes_value(true)
-            .help("Name of the simulation")
-        )
-        .arg(Arg::with_name("search_delay")
-            .short("d")
-            .long("search_delay")
-            .takes_value(true)
-            .help("Delay between searches in ticks")
-            .default_value("1")
-        )
-        .arg(Arg::with_name("skill")
-            .short("s")
-            .help("Default skill level assigned to the user")
-            .default_value("1500"))
-        .arg(Arg::with_name("real_skill_max")
-            .long("rmax")
-            .help("Maximum value of the skill level")
-            .default_value("2200"))
-        .arg(Arg::with_name("real_skill_min")
-            .long("rmin")
-            .help("Minimum value of the skill level")
-            .default_value("800"))
-
-        .arg(Arg::with_name("max_game_length")
-            .long("max_game_length")
-            .help("The amount of time before user reenter queue")
-            .default_value("300"))
-        .arg(Arg::with_name("continuous_play_prob")
-            .long("continuous_play_prob")
-            .help("The probability that after a game user will join the queue")
-            .default_value("0.0"))
-
-        .arg(Arg::with_name("use_real_skill")
-            .long("use_real_skill")
-            .help("Always use real skill level as skill level of the user"))
-
-        .arg(Arg::with_name("algorithm")
-            .short("a")
-            .long("alg")
-            .help("Algorithm type")
-            .possible_values(&["fifo", "rnd", "skill"])
-            .default_value("rnd"))
-        .arg(Arg::with_name("team_size")
-            .long("team_size")
-            .takes_value(true)
-            .help("The size of the team")
-            .default_value("5"))
-        .arg(Arg::with_name("queue_factor")
-            .long("queue_factor")
-            .takes_value(true)
-            .help("Queue overloading factor")
-            .default_value("1.0"))
-        .arg(Arg::with_name("prefill_factor")
-            .long("prefill_factor")
-            .takes_value(true)
-            .help("Amount of users to be added to the team on the first run of the search algorithm")
-            .default_value("0.0"))
-
-        .get_matches();
+        .about("A humble attempt to simulate typical matchmaking algorithms");
 
-    let ticks = params.value_of("time").unwrap().parse::<u32>().unwrap();
+    ARGUMENTS.iter().fold(app, |app, &(name, build)| app.arg(build(Arg::with_name(name))))
+}
 
+fn build_model(params: &ArgMatches, name: String, seed: u64, algorithm: &str, trace: Option<TraceArrivals>, population: Option<Population>) -> Model {
+    let ticks = params.value_of("time").unwrap().parse::<u32>().unwrap();
     let users_to_gen = params.value_of("users_to_gen").unwrap().parse::<u32>().unwrap();
     let users_at_start = params.value_of("users_at_start").unwrap().parse::<u32>().unwrap();
 
-    let search_delay = params.value_of("search_delay").unwrap().parse::<u32>().unwrap();
-
     let use_real_skill = params.is_present("use_real_skill");
     let default_skill_level = params.value_of("skill").unwrap().parse::<f32>().unwrap();
     let continuous_play_prob = params.value_of("continuous_play_prob").unwrap().parse::<f32>().unwrap();
@@ -111,172 +668,764 @@ fn main() {
     let real_skill_min = params.value_of("real_skill_min").unwrap().parse::<f32>().unwrap();
     let real_skill_max = params.value_of("real_skill_max").unwrap().parse::<f32>().unwrap();
 
-    let name = params.value_of("name")
-        .map(|str| String::from(str))
-        .unwrap_or((String::from("report_") + &thread_rng().next_u32().to_string()));
-
     let team_size = params.value_of("team_size").unwrap().parse::<usize>().unwrap();
     let queue_factor = params.value_of("queue_factor").unwrap().parse::<f32>().unwrap();
     let prefill_factor = params.value_of("prefill_factor").unwrap().parse::<f32>().unwrap();
 
-    let algorithm: Box<entities::Algoritm> = match params.value_of("algorithm").unwrap() {
+    let algorithm: Box<dyn entities::Algoritm> = match algorithm {
         "fifo" => Box::new(FIFOAlgorithm {
-            team_size: team_size,
+            team_size,
         }),
         "rnd" => Box::new(RandomPeekAlgorithm {
-            team_size: team_size,
+            team_size,
         }),
         "skill" => Box::new(SkillLevelAlgorithm {
             size_factor: queue_factor,
-            team_size: team_size,
-            prefill_factor: prefill_factor,
+            team_size,
+            prefill_factor,
         }),
         _ => panic!()
     };
 
-    let mut model = Model {
-        name: name.clone(),
+    let patience_min = params.value_of("patience_min").unwrap().parse::<f32>().unwrap();
+    let patience_max = params.value_of("patience_max").unwrap().parse::<f32>().unwrap();
+    let patience_gen = match params.value_of("patience").unwrap() {
+        "uniform" => PatienceGen::Range(RandomRangeGen::new(patience_min, patience_max, DistributionType::Uniform)),
+        "normal" => {
+            let normal = DistributionType::Normal { mean: (patience_min + patience_max) / 2.0, sigma: (patience_max - patience_min) / 2.0 };
+            PatienceGen::Range(RandomRangeGen::new(patience_min, patience_max, normal))
+        }
+        "exponential" => PatienceGen::Exponential { mean: params.value_of("patience_mean").unwrap().parse::<f32>().unwrap() },
+        _ => PatienceGen::Unlimited,
+    };
+
+    let segments = vec![
+        SegmentShare {
+            segment: Segment::Smurf,
+            share: params.value_of("smurf_share").unwrap().parse::<f32>().unwrap(),
+            offset: params.value_of("smurf_offset").unwrap().parse::<f32>().unwrap(),
+        },
+        SegmentShare {
+            segment: Segment::Veteran,
+            share: params.value_of("veteran_share").unwrap().parse::<f32>().unwrap(),
+            offset: params.value_of("veteran_offset").unwrap().parse::<f32>().unwrap(),
+        },
+    ];
+    let backfill: Option<Box<dyn BackfillPolicy>> = if params.is_present("backfill") {
+        Some(Box::new(SkillProximityBackfill {
+            max_skill_delta: params.value_of("backfill_skill_delta").unwrap().parse::<f32>().unwrap(),
+            min_remaining: params.value_of("backfill_min_remaining").unwrap().parse::<f32>().unwrap(),
+        }))
+    } else {
+        None
+    };
+
+    let mut tracked_segments = vec![Segment::Regular];
+    tracked_segments.extend(segments.iter().filter(|segment| segment.share > 0.0).map(|segment| segment.segment));
+
+    let arrivals: Box<dyn ArrivalGenerator> = match trace {
+        Some(trace) => Box::new(trace),
+        None => {
+            let skill_mean = params.value_of("skill_mean").unwrap().parse::<f32>().unwrap();
+            let skill_sigma = params.value_of("skill_sigma").unwrap().parse::<f32>().unwrap();
+            let distribution = match params.value_of("skill_dist").unwrap() {
+                "normal" => DistributionType::Normal { mean: skill_mean, sigma: skill_sigma },
+                "lognormal" => DistributionType::LogNormal { mean: skill_mean, sigma: skill_sigma },
+                "bimodal" => {
+                    let weight = params.value_of("skill_mix").unwrap().parse::<f32>().unwrap();
+                    let mean2 = params.value_of("skill_mean2").unwrap().parse::<f32>().unwrap();
+                    let sigma2 = params.value_of("skill_sigma2").unwrap().parse::<f32>().unwrap();
+                    DistributionType::Mixture(vec![(1.0 - weight, skill_mean, skill_sigma), (weight, mean2, sigma2)])
+                }
+                "empirical" => {
+                    let path = params.value_of("skill_histogram").unwrap();
+                    let content = std::fs::read_to_string(path)
+                        .unwrap_or_else(|e| panic!("Unable to read skill histogram {}: {}", path, e));
+                    DistributionType::Empirical(parse_histogram(&content))
+                }
+                _ => DistributionType::Uniform,
+            };
+            let real_skill_gen = RandomRangeGen::new(real_skill_min, real_skill_max, distribution);
+            let profile_period = params.value_of("profile_period").unwrap().parse::<u32>().unwrap().max(1);
+            let profile = match params.value_of("arrival_profile").unwrap() {
+                "diurnal" => RateProfile::Diurnal {
+                    amplitude: params.value_of("diurnal_amplitude").unwrap().parse::<f32>().unwrap(),
+                    peak: params.value_of("diurnal_peak").unwrap().parse::<u32>().unwrap(),
+                    period: profile_period,
+                },
+                "schedule" => RateProfile::load_schedule(params.value_of("profile_file").unwrap(), profile_period),
+                _ => RateProfile::Constant,
+            };
+            let bursts = params.values_of("burst").map(|values| values.map(Burst::parse).collect()).unwrap_or_default();
+
+            let mut arrivals = SyntheticArrivals::new(users_at_start, users_to_gen, ticks, real_skill_gen, profile, bursts);
+            arrivals.segments = segments.into_iter().filter(|segment| segment.share > 0.0).collect();
+            arrivals.population = population;
+            Box::new(arrivals)
+        }
+    };
+
+    let match_log = if params.is_present("match_log") { Some(create_match_log(&name)) } else { None };
+    let rating_log = if params.is_present("user_history") { Some(create_rating_log(&name)) } else { None };
+
+    Model {
+        name,
+        seed,
+        rng: new_rng(derive_seed(seed, 0)),
+        traffic_rng: new_rng(derive_seed(seed, 1)),
+        game_rng: new_rng(derive_seed(seed, 2)),
+        behaviour_seed: derive_seed(seed, 3),
         queue: Vec::new(),
 
-        user_pool: UserPool::new(use_real_skill),
-        users_at_start: users_at_start,
-        users_to_gen: users_to_gen,
+        user_pool: {
+            let mut user_pool = UserPool::new(use_real_skill);
+            user_pool.k_factor = params.value_of("k_factor").unwrap().parse::<f32>().unwrap();
+            user_pool
+        },
+        arrivals,
+        known_users: HashMap::new(),
 
-        algorithm: algorithm,
+        algorithm,
         decider: Box::new(RealSkillLevelDecider {}),
 
         stats: HashMap::new(),
-        delayed_enter: HashMap::new(),
+        ongoing_games: HashMap::new(),
+        next_game_id: 0,
+        delayed_end: HashMap::new(),
+        delayed_leave: HashMap::new(),
 
         default_skill: default_skill_level,
 
-        continuous_play_prob: continuous_play_prob,
-        max_game_length: max_game_length,
+        retention: RetentionModel {
+            continue_prob: continuous_play_prob,
+            return_prob: params.value_of("return_prob").unwrap().parse::<f32>().unwrap(),
+            return_delay_mean: params.value_of("return_delay").unwrap().parse::<f32>().unwrap(),
+            loss_streak_weight: params.value_of("loss_streak_weight").unwrap().parse::<f32>().unwrap(),
+            imbalance_weight: params.value_of("imbalance_weight").unwrap().parse::<f32>().unwrap(),
+            wait_weight: params.value_of("wait_weight").unwrap().parse::<f32>().unwrap(),
+        },
+        max_game_length,
+        skill_dynamics: SkillDynamics {
+            learning_gain: params.value_of("learning_gain").unwrap().parse::<f32>().unwrap(),
+            learning_games: params.value_of("learning_games").unwrap().parse::<f32>().unwrap(),
+            inactivity_decay: params.value_of("inactivity_decay").unwrap().parse::<f32>().unwrap(),
+            drift: params.value_of("skill_drift").unwrap().parse::<f32>().unwrap(),
+        },
+        segments: tracked_segments,
+        convergence_threshold: params.value_of("convergence_threshold").unwrap().parse::<f32>().unwrap(),
+        session_games: params.value_of("session_games").unwrap().parse::<f32>().unwrap(),
+        cohort_length: params.value_of("cohort_length").unwrap().parse::<u32>().unwrap().max(1),
+        cohorts: Vec::new(),
+
+        patience_gen,
+        rejoin_prob: params.value_of("rejoin_prob").unwrap().parse::<f32>().unwrap(),
+        rejoin_delay: params.value_of("rejoin_delay").unwrap().parse::<u32>().unwrap(),
+        delayed_rejoin: HashMap::new(),
+
+        leavers: LeaverPolicy {
+            leave_prob: params.value_of("leave_prob").unwrap().parse::<f32>().unwrap(),
+            resolution: match params.value_of("leaver_resolution").unwrap() {
+                "decider" => LeaverResolution::Decider,
+                _ => LeaverResolution::Forfeit,
+            },
+            rating_penalty: params.value_of("leaver_penalty").unwrap().parse::<f32>().unwrap(),
+            low_priority_games: params.value_of("low_priority_games").unwrap().parse::<u32>().unwrap(),
+        },
+        low_priority_queue: Vec::new(),
+        backfill,
+        backfill_requests: Vec::new(),
+
+        accept_prob: params.value_of("accept_prob").unwrap().parse::<f32>().unwrap(),
+        keep_queue_position: params.is_present("keep_queue_position"),
+        decline_ban: params.value_of("decline_ban").unwrap().parse::<u32>().unwrap(),
+
+        match_log,
+        window_waits: Vec::new(),
+        waits: Histogram::default(),
+        generated_skills: Histogram::default(),
+        user_history: params.is_present("user_history"),
+        rating_log,
+        window_predictions: PredictionStats::default(),
+        predictions: PredictionStats::default(),
+        prediction_window: params.value_of("prediction_window").unwrap().parse::<u32>().unwrap().max(1),
+        rank_interval: params.value_of("rank_interval").unwrap().parse::<u32>().unwrap().max(1),
+        top_n: params.value_of("top_n").unwrap().parse::<usize>().unwrap(),
+        skill_tiers: params.value_of("skill_tiers").unwrap().parse::<usize>().unwrap(),
+        wait_window: params.value_of("wait_window").unwrap().parse::<u32>().unwrap().max(1),
+        wait_bucket: params.value_of("wait_bucket").unwrap().parse::<f32>().unwrap().max(1.0),
+        sample_interval: params.value_of("sample_interval").unwrap().parse::<u32>().unwrap().max(1),
+    }
+}
 
-        real_skill_gen: RandomRangeGen::new(real_skill_min, real_skill_max, DistributionType::Uniform),
-    };
+/// All the command line arguments with their values, including the defaults, sorted by name.
+/// Options and flags which are not set are left out.
+fn command_line_arguments(params: &ArgMatches) -> Vec<(String, String)> {
+    let mut arguments: Vec<(String, String)> = ARGUMENTS.iter()
+        .filter_map(|&(key, _)| {
+            // flags are present without values
+            if params.value_of(key).is_some() {
+                Some((key.to_owned(), params.values_of(key).unwrap().collect::<Vec<&str>>().join(" ")))
+            } else if params.is_present(key) {
+                Some((key.to_owned(), String::from("true")))
+            } else {
+                None
+            }
+        })
+        .collect();
 
-    let log = model.run(ticks, search_delay);
+    arguments.sort();
+    arguments
+}
 
-    save_results(&name, log);
+/// Writes the events collected in memory into the sink
+fn save_results(sink: &mut dyn EventSink, events: Vec<Event>) {
+    for event in events {
+        sink.write(event);
+    }
+    sink.finish();
 }
 
-fn save_results(name: &str, events: Vec<Event>) {
-    //    events.sort_by(|a, b| a.tick.cmp(&b.tick));
-    const REPORT_DIR: &'static str = "reports";
+/// Writes what every user experienced into `reports/<name>_users.csv`
+fn save_user_history(name: &str, user_pool: &UserPool) {
+    std::fs::create_dir("reports").ok();
 
-    std::fs::create_dir(REPORT_DIR).ok();
+    let mut lines = vec![String::from("id,segment,real_skill,rating,created_tick,games,wins,losses,total_wait,avg_wait,converged_at")];
+    for user in user_pool.users() {
+        let history = &user.history;
+        let games = history.wins + history.losses;
+        let avg_wait = if games == 0 { 0.0 } else { history.total_wait as f32 / games as f32 };
 
-    use std::io::Write;
-    let path = REPORT_DIR.to_owned() + "/" + name + ".csv";
-    let mut report = std::fs::File::create(&path).unwrap();
+        lines.push(format!("{},{},{},{},{},{},{},{},{},{},{}",
+                           user.id, user.segment.name(), user.real_skill, user.get_skill(), user.created_tick, user.get_games_played(),
+                           history.wins, history.losses, history.total_wait, avg_wait,
+                           history.converged_at.map(|tick| tick.to_string()).unwrap_or_default()));
+    }
 
-    for event in events {
+    let path = String::from("reports/") + name + "_users.csv";
+    std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+    println!("User history saved into: {}", path);
+}
+
+const MATCH_LOG_HEADER: &str = "tick,length,team1,team2,team1_ratings,team2_ratings,team1_real_skills,team2_real_skills,start_win_probability,win_probability,winner,team1_deltas,team2_deltas,leavers,backfilled";
+
+/// Every finished game is written into `reports/<name>_matches.csv` as it ends, lists of the players are space separated.
+/// Games still running when the run ends are left out. The log stays uncompressed so it can be replayed.
+fn create_match_log(name: &str) -> CsvFile {
+    let mut log = CsvFile::create(&format!("{}_matches", name), false);
+    log.line(format_args!("{}", MATCH_LOG_HEADER));
+    log
+}
+
+/// The rating of every player after every game is written into `reports/<name>_ratings.csv`
+fn create_rating_log(name: &str) -> CsvFile {
+    let mut log = CsvFile::create(&format!("{}_ratings", name), false);
+    log.line(format_args!("tick,id,rating"));
+    log
+}
+
+/// `probability` is the prediction the ratings were updated with, by the members and ratings at the end of the game
+fn match_log_line(ongoing: &OngoingGame, probability: f32, winner: u32, deltas: &(Vec<f32>, Vec<f32>)) -> String {
+    fn join<T: ToString>(values: &[T]) -> String {
+        values.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(" ")
+    }
+
+    let ratings = |skills: &[(f32, f32)]| join(&skills.iter().map(|v| v.0).collect::<Vec<f32>>());
+    let real_skills = |skills: &[(f32, f32)]| join(&skills.iter().map(|v| v.1).collect::<Vec<f32>>());
+
+    format!("{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            ongoing.start, ongoing.length, join(&ongoing.game.team1), join(&ongoing.game.team2),
+            ratings(&ongoing.team1_skills), ratings(&ongoing.team2_skills),
+            real_skills(&ongoing.team1_skills), real_skills(&ongoing.team2_skills),
+            ongoing.start_win_probability, probability, winner, join(&deltas.0), join(&deltas.1),
+            join(&ongoing.leavers), join(&ongoing.game.backfilled))
+}
+
+/// Collapses several runs of the same scenario into one report, the runs add their values as they go.
+/// Timed values are averaged per run and tick first, then summarized across runs,
+/// single values are averaged per run and summarized the same way.
+#[derive(Default)]
+struct Replications {
+    /// Parameters of the first run
+    params: Vec<Event>,
+    /// Events which are aggregated already, passed on as they are
+    other: Vec<Event>,
+    timed: HashMap<(u32, StatKey), RunningSummary>,
+    floats: HashMap<StatKey, RunningSummary>,
+}
+
+impl Replications {
+    fn events(self, name: &str, seed: u64, count: u64) -> Vec<Event> {
+        let mut events = vec![
+            Event::StrParam("name", name.to_owned()),
+            Event::StrParam("seed", seed.to_string()),
+            Event::StrParam("replications", count.to_string()),
+        ];
+        events.extend(self.params);
+        events.extend(self.other);
+
+        let mut floats: Vec<(StatKey, RunningSummary)> = self.floats.into_iter().collect();
+        floats.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (key, running) in floats {
+            let summary = running.summary();
+            let band = summary.confidence_95();
+            events.push(Event::FloatBand(key, summary.mean, summary.mean - band, summary.mean + band));
+        }
+
+        let mut timed: Vec<((u32, StatKey), RunningSummary)> = self.timed.into_iter().collect();
+        timed.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for ((tick, key), running) in timed {
+            let summary = running.summary();
+            let band = summary.confidence_95();
+            events.push(Event::TimedBand(tick, key, summary.mean, summary.mean - band, summary.mean + band));
+        }
+
+        events
+    }
+}
+
+/// Sink of a single replication, adds the averages of every tick to the shared aggregate once the tick is over
+struct ReplicationRun<'a> {
+    first: bool,
+    replications: &'a Mutex<Replications>,
+    tick: u32,
+    per_tick: HashMap<StatKey, (f32, u32)>,
+    per_run: HashMap<StatKey, (f32, u32)>,
+}
+
+impl<'a> ReplicationRun<'a> {
+    fn new(first: bool, replications: &'a Mutex<Replications>) -> ReplicationRun<'a> {
+        ReplicationRun { first, replications, tick: 0, per_tick: HashMap::new(), per_run: HashMap::new() }
+    }
+
+    fn flush_tick(&mut self) {
+        if self.per_tick.is_empty() {
+            return;
+        }
+
+        let mut replications = self.replications.lock().unwrap();
+        for (key, (sum, count)) in self.per_tick.drain() {
+            replications.timed.entry((self.tick, key)).or_default().add(sum / count as f32);
+        }
+    }
+}
+
+impl<'a> EventSink for ReplicationRun<'a> {
+    fn write(&mut self, event: Event) {
         match event {
-            Event::StrParam(name, value) => report.write(format!("{},{}\n", name, value).as_bytes()).unwrap(),
-            Event::Float(name, value) => report.write(format!("{},{}\n", name, value).as_bytes()).unwrap(),
-            Event::TimedFloat(tick, name, value) => report.write(format!("{},{},{}\n", tick, name, value).as_bytes()).unwrap(),
-        };
+            Event::TimedFloat(tick, key, value) => {
+                if tick != self.tick {
+                    self.flush_tick();
+                    self.tick = tick;
+                }
+                let entry = self.per_tick.entry(key).or_insert((0.0, 0));
+                entry.0 += value;
+                entry.1 += 1;
+            }
+            Event::Float(key, value) => {
+                let entry = self.per_run.entry(key).or_insert((0.0, 0));
+                entry.0 += value;
+                entry.1 += 1;
+            }
+            Event::StrParam(key, value) => {
+                if self.first && key != "name" && key != "seed" {
+                    self.replications.lock().unwrap().params.push(Event::StrParam(key, value));
+                }
+            }
+            other => self.replications.lock().unwrap().other.push(other),
+        }
+    }
+
+    fn finish(&mut self) {
+        self.flush_tick();
+
+        let mut replications = self.replications.lock().unwrap();
+        for (key, (sum, count)) in self.per_run.drain() {
+            replications.floats.entry(key).or_default().add(sum / count as f32);
+        }
+    }
+}
+
+struct StatSummary {
+    key: StatKey,
+    avg: f32,
+    /// Last value of a timed stat, none for the single values, e.g. `total_wait_p95`
+    last: Option<f32>,
+}
+
+/// Averages every timed value over the run, keeps the last value as well, averages the single values
+/// of the run separately and passes the events on
+struct Summarizing<S> {
+    sink: S,
+    values: HashMap<StatKey, (f32, u32, f32)>,
+    singles: HashMap<StatKey, (f32, u32)>,
+}
+
+impl<S> Summarizing<S> {
+    fn new(sink: S) -> Summarizing<S> {
+        Summarizing { sink, values: HashMap::new(), singles: HashMap::new() }
+    }
+
+    fn summary(self) -> Vec<StatSummary> {
+        let timed = self.values.into_iter()
+            .map(|(key, (sum, count, last))| StatSummary { key, avg: sum / count as f32, last: Some(last) });
+        let singles = self.singles.into_iter()
+            .map(|(key, (sum, count))| StatSummary { key, avg: sum / count as f32, last: None });
+
+        let mut summary: Vec<StatSummary> = timed.chain(singles).collect();
+        summary.sort_by(|a, b| (&a.key, a.last.is_none()).cmp(&(&b.key, b.last.is_none())));
+        summary
+    }
+}
+
+impl<S: EventSink> EventSink for Summarizing<S> {
+    fn write(&mut self, event: Event) {
+        match event {
+            Event::TimedFloat(_, ref key, value) | Event::TimedBand(_, ref key, value, _, _) => {
+                let entry = self.values.entry(key.clone()).or_insert((0.0, 0, 0.0));
+                entry.0 += value;
+                entry.1 += 1;
+                entry.2 = value;
+            }
+            Event::Float(ref key, value) | Event::FloatBand(ref key, value, _, _) => {
+                let entry = self.singles.entry(key.clone()).or_insert((0.0, 0));
+                entry.0 += value;
+                entry.1 += 1;
+            }
+            Event::StrParam(..) => {}
+        }
+
+        self.sink.write(event);
+    }
+
+    fn finish(&mut self) {
+        self.sink.finish();
     }
-    println!("Report saved into: {}", path);
+}
+
+/// Writes the summaries of several algorithms side by side, one column per algorithm.
+/// Timed stats get an `_avg` and a `_last` row, single values a row of their own name.
+fn save_comparison(name: &str, reports: &[(String, Vec<StatSummary>)]) {
+    let mut keys: Vec<(&StatKey, bool)> = reports.iter().flat_map(|report| report.1.iter().map(|v| (&v.key, v.last.is_none()))).collect();
+    keys.sort();
+    keys.dedup();
+
+    let mut lines = vec![String::from("stat,") + &reports.iter().map(|report| report.0.clone()).collect::<Vec<String>>().join(",")];
+
+    for (key, single) in keys {
+        let find = |summary: &[StatSummary]| summary.iter().find(|v| &v.key == key && v.last.is_none() == single).map(|v| (v.avg, v.last));
+
+        let avg: Vec<String> = reports.iter().map(|report| find(&report.1).map(|v| v.0.to_string()).unwrap_or_default()).collect();
+        if single {
+            lines.push(format!("{},{}", key, avg.join(",")));
+            continue;
+        }
+
+        let last: Vec<String> = reports.iter().map(|report| find(&report.1).and_then(|v| v.1).map(|v| v.to_string()).unwrap_or_default()).collect();
+        lines.push(format!("{}_avg,{}", key, avg.join(",")));
+        lines.push(format!("{}_last,{}", key, last.join(",")));
+    }
+
+    let path = String::from("reports/") + name + "_compare.csv";
+    std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+
+    for line in &lines {
+        println!("{}", line.replace(',', "\t"));
+    }
+    println!("Comparison saved into: {}", path);
+}
+
+// random streams of the users' own decisions
+const CONTINUE_STREAM: u64 = 0;
+const REJOIN_STREAM: u64 = 1;
+const RETURN_STREAM: u64 = 2;
+const RETURN_DELAY_STREAM: u64 = 3;
+const DRIFT_STREAM: u64 = 4;
+const LEAVE_STREAM: u64 = 5;
+const ACCEPT_STREAM: u64 = 6;
+
+/// Width of the buckets of the real skill levels of the generated users
+const GENERATED_SKILL_BUCKET: f32 = 5.0;
+
+/// Percentiles of the completed waits, reported per window and for the whole run
+const WAIT_PERCENTILES: [(&str, f32); 4] = [("wait_p50", 50.0), ("wait_p90", 90.0), ("wait_p95", 95.0), ("wait_p99", 99.0)];
+
+/// A game in progress, it is resolved when it ends
+#[derive(Debug)]
+struct OngoingGame {
+    game: Game,
+    start: u32,
+    length: u32,
+    end: u32,
+    /// Predicted probability that the first team wins, when the game started
+    start_win_probability: f32,
+    /// Rating and real skill level of the members of the teams when they joined the game
+    team1_skills: Vec<(f32, f32)>,
+    team2_skills: Vec<(f32, f32)>,
+    /// Real skill difference of the teams when the game started
+    imbalance: f32,
+    /// Players who left before the end
+    leavers: Vec<UserId>,
+}
+
+/// Place of a leaver waiting for a replacement from the queue
+#[derive(Debug)]
+struct BackfillRequest {
+    game_id: u64,
+    team: u32,
+    /// Skill level of the leaver
+    skill: f32,
+    since: u32,
 }
 
 struct Model {
     name: String,
+    seed: u64,
+
+    // independent random streams, so the traffic does not depend on the decisions of the algorithm
+    rng: SimRng,
+    traffic_rng: SimRng,
+    game_rng: SimRng,
+    behaviour_seed: u64,
+
     queue: Vec<UserId>,
 
     user_pool: UserPool,
 
-    algorithm: Box<Algoritm>,
-    decider: Box<GameDecider>,
+    algorithm: Box<dyn Algoritm>,
+    decider: Box<dyn GameDecider>,
+
+    arrivals: Box<dyn ArrivalGenerator>,
+    /// Users by their external id, so returning users of a trace keep their data
+    known_users: HashMap<u64, UserId>,
 
-    users_at_start: u32,
-    users_to_gen: u32,
     max_game_length: u32,
-    continuous_play_prob: f32,
+    retention: RetentionModel,
+    skill_dynamics: SkillDynamics,
+    /// Segments of the population reported separately, the regular users are always present
+    segments: Vec<Segment>,
+    convergence_threshold: f32,
+    session_games: f32,
+    cohort_length: u32,
+    /// Size and amount of churned users of every cohort
+    cohorts: Vec<(u32, u32)>,
 
     default_skill: f32,
-    real_skill_gen: RandomRangeGen,
 
-    delayed_enter: HashMap<u32, Vec<UserId>>,
+    patience_gen: PatienceGen,
+    rejoin_prob: f32,
+    rejoin_delay: u32,
+
+    ongoing_games: HashMap<u64, OngoingGame>,
+    next_game_id: u64,
+    /// Games ending at the tick
+    delayed_end: HashMap<u32, Vec<u64>>,
+    /// Players leaving their games at the tick
+    delayed_leave: HashMap<u32, Vec<(u64, UserId)>>,
+    /// Offline users who will join the queue again, e.g. after giving up waiting
+    delayed_rejoin: HashMap<u32, Vec<UserId>>,
+
+    leavers: LeaverPolicy,
+    /// Leavers are matched only with each other until they serve their low priority games
+    low_priority_queue: Vec<UserId>,
+    backfill: Option<Box<dyn BackfillPolicy>>,
+    backfill_requests: Vec<BackfillRequest>,
+
+    /// Probability that a player accepts the ready check of a found game
+    accept_prob: f32,
+    /// Players who accepted a failed ready check keep their place in the queue, otherwise they go to its end
+    keep_queue_position: bool,
+    /// Ticks a player who declined a ready check stays out of the queue
+    decline_ban: u32,
+
+    /// Completed waits of the current window and of the whole run, from joining the queue to the game start
+    window_waits: Vec<f32>,
+    waits: Histogram,
+    /// Real skill levels of the generated users, rounded to whole points
+    generated_skills: Histogram,
+    /// Written as the games end
+    match_log: Option<CsvFile>,
+    /// Exports the history of every user at the end of the run
+    user_history: bool,
+    /// Ratings of the players after every game, written with the user history
+    rating_log: Option<CsvFile>,
+
+    /// Win probabilities implied by the ratings scored against the results, of the current window and of the whole run
+    window_predictions: PredictionStats,
+    predictions: PredictionStats,
+    prediction_window: u32,
+
+    /// Ticks between the rank correlations of the ratings and the real skill levels
+    rank_interval: u32,
+    top_n: usize,
+    /// Amount of real skill percentile tiers of the fairness report
+    skill_tiers: usize,
+    wait_window: u32,
+    wait_bucket: f32,
+    /// Ticks between the snapshots of `stats` in the report
+    sample_interval: u32,
+
     stats: HashMap<&'static str, f32>,
 }
 
 impl Model {
-    pub fn run(&mut self, ticks: u32, search_delay: u32) -> Vec<Event> {
+    /// Simulates `ticks` ticks, the events of every tick are handed to the sink once the tick is over
+    pub fn run(&mut self, ticks: u32, search_delay: u32, sink: &mut dyn EventSink) {
         println!("Simulating: {}, ticks: {}", self.name, ticks);
         println!("Algorithm: {:?}, will run each {} ticks, use real skill:{}", self.algorithm, search_delay, self.user_pool.use_real_skill);
         println!("Game result decider: {:?}", self.decider);
-        println!("Real skill level generation strategy: {:?}", self.real_skill_gen);
-        println!("Users at the start of the simulation: {}, users to be generated during the simulation: {}", self.users_at_start, self.users_to_gen);
-        println!("Maximum Game length: {}, after game join queue probability after: {}", self.max_game_length, self.continuous_play_prob);
+        println!("Arrivals: {:?}", self.arrivals);
+        println!("Maximum Game length: {}, retention: {:?}, games per session: {}", self.max_game_length, self.retention, self.session_games);
+        println!("Real skill dynamics: {:?}", self.skill_dynamics);
+        println!("Patience: {:?}, rejoin probability: {}, rejoin delay: {}", self.patience_gen, self.rejoin_prob, self.rejoin_delay);
+        println!("Leavers: {:?}, backfill: {:?}", self.leavers, self.backfill);
+        println!("Accept probability: {}, keep queue position: {}, decline ban: {}", self.accept_prob, self.keep_queue_position, self.decline_ban);
 
         let mut events = Vec::new();
         events.push(Event::StrParam("name", self.name.clone()));
         events.push(Event::StrParam("ticks", ticks.to_string()));
+        events.push(Event::StrParam("seed", self.seed.to_string()));
+        events.push(Event::StrParam("algorithm", format!("{:?}", self.algorithm)));
 
-        let users_per_tick = (self.users_to_gen as f32) / (ticks as f32);
-
-        let mut rng = thread_rng();
-        let mut users_to_gen: f32 = self.users_at_start as f32;
         let mut last_search: u32 = 0;
 
         // ================= MAIN LOOOOOOOOOP ============================
         for tick in 1..ticks + 1 {
+            if let Some(leaves) = self.delayed_leave.remove(&tick) {
+                for (game_id, id) in leaves {
+                    self.on_game_left(game_id, id, tick);
+                }
+            }
+
             // Users that finished their game must be added back to the queue
-            let users_to_reuse = self.delayed_enter.remove(&tick);
-            match users_to_reuse {
-                Some(users) => {
-                    for id in users {
-                        if rng.next_f32() < self.continuous_play_prob {
-                            self.join_queue(id, tick)
-                        }
+            if let Some(games) = self.delayed_end.remove(&tick) {
+                for game_id in games {
+                    self.on_game_ended(game_id, tick, &mut events);
+                }
+            }
+
+            if let Some(users) = self.delayed_rejoin.remove(&tick) {
+                for id in users {
+                    // a trace may have brought the user back already
+                    if self.user_pool.get_user(&id).state == UserState::Offline {
+                        self.join_queue(id, tick);
                     }
                 }
-                _ => {}
             }
 
             // generating new users if needed
-            users_to_gen += users_per_tick;
-            while users_to_gen >= 1.0 {
-                users_to_gen -= 1.0;
-
-                let real_skill = self.real_skill_gen.generate();
-                let id = self.user_pool.generate(self.default_skill, real_skill);
+            let arrivals = self.arrivals.arrivals(tick, &mut self.traffic_rng);
+            self.stats.insert("users_arrived", arrivals.len() as f32);
+
+            for arrival in arrivals {
+                let known = arrival.key.and_then(|key| self.known_users.get(&key).cloned());
+
+                let id = match known {
+                    // a returning user that is still playing or waiting does not enter twice
+                    Some(id) if self.user_pool.get_user(&id).state != UserState::Offline => continue,
+                    Some(id) => {
+                        // a user of a trace who had left for good enters the population again
+                        if self.user_pool.get_user(&id).churned {
+                            self.unchurn(id);
+                        }
+                        id
+                    }
+                    None => {
+                        let id = self.user_pool.generate(arrival.skill.unwrap_or(self.default_skill), arrival.real_skill);
+                        let patience = self.patience_gen.generate(&mut self.traffic_rng);
+                        let activity = arrival.activity.unwrap_or(1.0);
+                        let session_length = if self.session_games > 0.0 {
+                            (-self.session_games * activity * (1.0 - self.traffic_rng.next_f32()).ln()).round().max(1.0) as u32
+                        } else {
+                            0
+                        };
+
+                        let user = self.user_pool.get_user_mut(&id);
+                        user.party = arrival.party;
+                        user.segment = arrival.segment;
+                        user.patience = patience;
+                        user.created_tick = tick;
+                        user.session_length = session_length;
+                        user.rating_deviation = arrival.rating_deviation;
+                        user.activity = activity;
+
+                        let cohort = ((tick - 1) / self.cohort_length) as usize;
+                        if self.cohorts.len() <= cohort {
+                            self.cohorts.resize(cohort + 1, (0, 0));
+                        }
+                        self.cohorts[cohort].0 += 1;
+                        if let Some(key) = arrival.key {
+                            self.known_users.insert(key, id);
+                        }
 
-                events.push(Event::Float("user_generated_skill", real_skill));
+                        self.on_rating_entered(id);
+                        self.generated_skills.add(arrival.real_skill.max(0.0).round() as u32);
+                        id
+                    }
+                };
 
                 self.join_queue(id, tick);
             }
 
             if (last_search + search_delay) <= tick {
-                // trying to automatch until first failure 
-                loop {
-                    let result = self.algorithm.search(&mut self.queue, &self.user_pool);
-                    match result {
-                        AlgorithmResult::None => break,
-                        AlgorithmResult::Found(game) => {
-                            let (skill_t1, rskill_t1) = self.build_team_data(&game.team1);
-                            let (skill_t2, rskill_t2) = self.build_team_data(&game.team2);
-
-                            events.push(Event::TimedFloat(tick, "game_created_avg_skill_delta", (skill_t1.avg - skill_t2.avg).abs()));
-                            events.push(Event::TimedFloat(tick, "game_created_avg_rskill_delta", (rskill_t1.avg - rskill_t2.avg).abs()));
-
-                            events.push(Event::TimedFloat(tick, "game_created_max_skill_delta", f32::max(skill_t1.max, skill_t2.max) - f32::min(skill_t1.min, skill_t2.min)));
-                            events.push(Event::TimedFloat(tick, "game_created_max_rskill_delta", f32::max(rskill_t1.max, rskill_t2.max) - f32::min(rskill_t1.min, rskill_t2.min)));
-
-                            *(self.stats.entry("games_created").or_insert(0.0)) += 1.0;
-
-                            self.on_game_started(tick, game);
-                        },
+                // games in progress take their replacements before new games are created
+                self.backfill_games(tick, &mut events);
+
+                // trying to automatch until first failure, leavers are matched only with each other
+                for low_priority in &[false, true] {
+                    loop {
+                        let queue = if *low_priority { &mut self.low_priority_queue } else { &mut self.queue };
+                        let result = self.algorithm.search(queue, &self.user_pool, &mut self.rng);
+                        match result {
+                            AlgorithmResult::None => break,
+                            AlgorithmResult::Found(game) => {
+                                if !self.ready_check(tick, &game, &mut events) {
+                                    continue;
+                                }
+
+                                let (skill_t1, rskill_t1) = self.build_team_data(&game.team1);
+                                let (skill_t2, rskill_t2) = self.build_team_data(&game.team2);
+
+                                events.push(Event::TimedFloat(tick, Cow::Borrowed("game_created_avg_skill_delta"), (skill_t1.avg - skill_t2.avg).abs()));
+                                events.push(Event::TimedFloat(tick, Cow::Borrowed("game_created_avg_rskill_delta"), (rskill_t1.avg - rskill_t2.avg).abs()));
+
+                                events.push(Event::TimedFloat(tick, Cow::Borrowed("game_created_max_skill_delta"), f32::max(skill_t1.max, skill_t2.max) - f32::min(skill_t1.min, skill_t2.min)));
+                                events.push(Event::TimedFloat(tick, Cow::Borrowed("game_created_max_rskill_delta"), f32::max(rskill_t1.max, rskill_t2.max) - f32::min(rskill_t1.min, rskill_t2.min)));
+
+                                // damage of the segments, measured by the imbalance of the games they play
+                                for segment in self.segments.iter().filter(|segment| **segment != Segment::Regular) {
+                                    if game.team1.iter().chain(&game.team2).any(|id| self.user_pool.get_user(id).segment == *segment) {
+                                        events.push(Event::TimedFloat(tick, Cow::Owned(format!("{}_game_rskill_delta", segment.name())), (rskill_t1.avg - rskill_t2.avg).abs()));
+                                    }
+                                }
+
+                                *(self.stats.entry("games_created").or_insert(0.0)) += 1.0;
+
+                                self.on_game_started(tick, game);
+                            },
+                        }
                     }
                 }
                 last_search = tick;
             }
 
+            self.abandon_queue(tick);
+
             // stats here 
             self.stats.insert("users_in_queue", self.queue.len() as f32);
+            if self.backfill.is_some() {
+                self.stats.insert("backfill_slots_open", self.backfill_requests.len() as f32);
+            }
+            if self.leavers.low_priority_games > 0 {
+                self.stats.insert("users_in_low_priority", self.low_priority_queue.len() as f32);
+            }
             self.stats.insert("avg_skill_error", self.user_pool.get_avg_skill_error());
+            self.stats.insert("avg_real_skill", self.user_pool.get_avg_real_skill());
+            if self.skill_dynamics.learning_gain != 0.0 {
+                let learning_games = (self.skill_dynamics.learning_games * 2.0) as u32;
+                self.stats.insert("learning_skill_error", self.user_pool.get_avg_skill_error_of(|user| user.get_games_played() < learning_games));
+            }
 
             let times_in_queue: Vec<u32> = self.queue.iter().map(|id| tick - self.user_pool.get_user(id).get_join_time()).collect();
             let time_in_queue_max = times_in_queue.iter().fold(0, |max, v| if max < *v { *v } else { max });
@@ -285,70 +1434,573 @@ impl Model {
             let active_users = self.get_active_users();
             self.stats.insert("active_users", active_users as f32);
 
-            if self.queue.len() == 0 {
+            let users_away: usize = self.delayed_rejoin.values().map(|users| users.len()).sum();
+            self.stats.insert("users_away", users_away as f32);
+
+            if self.queue.is_empty() {
                 self.stats.insert("time_in_queue_avg", 0.0);
             } else {
                 let time_in_queue_sum: u32 = times_in_queue.iter().sum();
                 self.stats.insert("time_in_queue_avg", (time_in_queue_sum as f32) / (self.queue.len() as f32));
             }
 
+            // the rating points in the population and where they came from
+            let rating_total = ["rating_from_entries", "rating_from_departures", "rating_from_games"].iter()
+                .map(|key| self.stats.get(key).cloned().unwrap_or(0.0))
+                .sum::<f32>();
+            let rating_population = self.stats.get("rating_population").cloned().unwrap_or(0.0);
+            self.stats.insert("rating_total", rating_total);
+            self.stats.insert("rating_mean", if rating_population > 0.0 { rating_total / rating_population } else { 0.0 });
+
             // firing stat events
-            for (key, value) in &self.stats {
-                events.push(Event::TimedFloat(tick, key, value.clone()));
+            if tick % self.sample_interval == 0 || tick == ticks {
+                for (key, value) in &self.stats {
+                    events.push(Event::TimedFloat(tick, Cow::Borrowed(key), *value));
+                }
+
+                if self.segments.len() > 1 {
+                    // a single pass over the users for all the segments
+                    let mut errors = vec![(0.0, 0); self.segments.len()];
+                    for user in self.user_pool.users() {
+                        if let Some(index) = self.segments.iter().position(|segment| *segment == user.segment) {
+                            errors[index].0 += (user.real_skill - user.get_skill()).abs();
+                            errors[index].1 += 1;
+                        }
+                    }
+
+                    for (segment, (sum, count)) in self.segments.iter().zip(errors) {
+                        let error = if count == 0 { 0.0 } else { sum / count as f32 };
+                        events.push(Event::TimedFloat(tick, Cow::Owned(format!("{}_skill_error", segment.name())), error));
+                    }
+                }
+            }
+
+            if tick % self.wait_window == 0 && !self.window_waits.is_empty() {
+                let mut waits: Vec<f32> = self.window_waits.drain(..).collect();
+                waits.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+                for &(name, p) in &WAIT_PERCENTILES {
+                    events.push(Event::TimedFloat(tick, Cow::Borrowed(name), percentile(&waits, p)));
+                }
+                for wait in waits {
+                    self.waits.add(wait as u32);
+                }
+            }
+
+            if tick % self.rank_interval == 0 {
+                // the ordering of the players who are online, as a leaderboard would show it
+                let (ratings, real_skills): (Vec<f32>, Vec<f32>) = self.user_pool.users().iter()
+                    .filter(|user| user.state != UserState::Offline)
+                    .map(|user| (user.get_skill(), user.real_skill))
+                    .unzip();
+
+                if ratings.len() > 1 {
+                    events.push(Event::TimedFloat(tick, Cow::Borrowed("rank_spearman"), spearman(&ratings, &real_skills)));
+                    events.push(Event::TimedFloat(tick, Cow::Borrowed("rank_kendall"), kendall(&ratings, &real_skills)));
+                    events.push(Event::TimedFloat(tick, Cow::Borrowed("top_n_precision"), top_n_precision(&ratings, &real_skills, self.top_n)));
+                }
+            }
+
+            if tick % self.prediction_window == 0 && self.window_predictions.count > 0 {
+                push_prediction_metrics(tick, &self.window_predictions, &mut events);
+                self.window_predictions = PredictionStats::default();
+            }
+
+            if tick % self.cohort_length == 0 {
+                for (index, &(size, churned)) in self.cohorts.iter().enumerate() {
+                    let retention = if size == 0 { 0.0 } else { (1.0 - churned as f32 / size as f32).clamp(0.0, 1.0) };
+                    events.push(Event::TimedFloat(tick, Cow::Owned(format!("cohort_{}_retention", index)), retention));
+                }
             }
 
             // showing progress
             if tick % (ticks / 10) == 0 {
-                println!("{}", tick);
+                println!("{}: {}", self.name, tick);
+            }
+
+            for event in events.drain(..) {
+                sink.write(event);
             }
         }
 
-        events
+        push_prediction_summary(&self.predictions, &mut events);
+
+        for wait in self.window_waits.drain(..) {
+            self.waits.add(wait as u32);
+        }
+
+        for &(name, p) in &WAIT_PERCENTILES {
+            events.push(Event::Float(Cow::Owned(format!("total_{}", name)), self.waits.percentile(p)));
+        }
+        for (index, count) in self.waits.buckets(self.wait_bucket).into_iter().enumerate() {
+            events.push(Event::Float(Cow::Owned(format!("wait_histogram_{}", index as f32 * self.wait_bucket)), count as f32));
+        }
+        for (index, count) in self.generated_skills.buckets(GENERATED_SKILL_BUCKET).into_iter().enumerate().filter(|&(_, count)| count > 0) {
+            let start = index as f32 * GENERATED_SKILL_BUCKET;
+            events.push(Event::Float(Cow::Owned(format!("generated_skill_histogram_{}", start)), count as f32));
+        }
+
+        // users who never converged are missing from `<segment>_games_to_converge`
+        if self.segments.len() > 1 {
+            for segment in &self.segments {
+                let users: Vec<&UserData> = self.user_pool.users().iter().filter(|user| user.segment == *segment).collect();
+                let unconverged = users.iter().filter(|user| user.converged_after.is_none()).count() as f32;
+                let share = if users.is_empty() { 0.0 } else { unconverged / users.len() as f32 };
+
+                events.push(Event::Float(Cow::Owned(format!("{}_unconverged", segment.name())), unconverged));
+                events.push(Event::Float(Cow::Owned(format!("{}_unconverged_share", segment.name())), share));
+            }
+        }
+
+        self.push_fairness(&mut events);
+
+        for event in events {
+            sink.write(event);
+        }
     }
 
-    fn build_team_data(&self, ids: &Vec<usize>) -> (SkillValue, SkillValue) {
-        let skill_levels = ids.iter().map(|id| self.user_pool.get_user(id).get_skill()).collect();
-        let real_skill_levels = ids.iter().map(|id| self.user_pool.get_user(id).real_skill).collect();
+    /// Waits, win rates, imbalance and rating error of the users grouped by the percentile of their real skill level
+    fn push_fairness(&self, events: &mut Vec<Event>) {
+        let users = self.user_pool.users();
+        if users.is_empty() || self.skill_tiers == 0 {
+            return;
+        }
+
+        let real_skills: Vec<f32> = users.iter().map(|user| user.real_skill).collect();
+        let user_tiers = tiers(&real_skills, self.skill_tiers);
+
+        for tier in 0..self.skill_tiers {
+            let members: Vec<&UserData> = users.iter().zip(&user_tiers)
+                .filter(|&(_, user_tier)| *user_tier == tier)
+                .map(|(user, _)| user)
+                .collect();
+            if members.is_empty() {
+                continue;
+            }
+
+            let games = members.iter().map(|user| user.history.wins + user.history.losses).sum::<u32>() as f32;
+            let per_game = |value: f32| if games > 0.0 { value / games } else { 0.0 };
+            let min_skill = members.iter().map(|user| user.real_skill).fold(f32::MAX, f32::min);
+            let rating_error = members.iter().map(|user| (user.get_skill() - user.real_skill).abs()).sum::<f32>() / members.len() as f32;
+
+            let mut push = |name: &str, value: f32| events.push(Event::Float(Cow::Owned(format!("tier_{}_{}", tier, name)), value));
+            push("users", members.len() as f32);
+            push("min_real_skill", min_skill);
+            push("games", games);
+            push("avg_wait", per_game(members.iter().map(|user| user.history.total_wait as f32).sum()));
+            push("win_rate", per_game(members.iter().map(|user| user.history.wins as f32).sum()));
+            push("avg_imbalance", per_game(members.iter().map(|user| user.history.total_imbalance).sum()));
+            push("rating_error", rating_error);
+        }
+    }
+
+    fn build_team_data(&self, ids: &[usize]) -> (SkillValue, SkillValue) {
+        let skill_levels: Vec<f32> = ids.iter().map(|id| self.user_pool.get_user(id).get_skill()).collect();
+        let real_skill_levels: Vec<f32> = ids.iter().map(|id| self.user_pool.get_user(id).real_skill).collect();
 
         (SkillValue::build(&skill_levels), SkillValue::build(&real_skill_levels))
     }
 
     fn on_game_started(&mut self, tick: u32, game: Game) {
-        let winner = self.decider.decide(&game, &self.user_pool);
-        let game_length = thread_rng().gen_range(1, self.max_game_length);
+        let game_length = self.game_rng.gen_range(1, self.max_game_length);
+        let game_id = self.next_game_id;
+        self.next_game_id += 1;
+
+        *(self.stats.entry("users_matched").or_insert(0.0)) += (game.team1.len() + game.team2.len()) as f32;
 
-        game.process(&self.user_pool, winner);
+        let (_, rskill_t1) = self.build_team_data(&game.team1);
+        let (_, rskill_t2) = self.build_team_data(&game.team2);
+        let imbalance = (rskill_t1.avg - rskill_t2.avg).abs();
 
-        for id in game.team1 {
-            self.delayed_enter.entry(tick + game_length).or_insert(Vec::new()).push(id);
+        for &id in game.team1.iter().chain(&game.team2) {
+            self.enter_game(game_id, id, tick, game_length);
         }
-        for id in game.team2 {
-            self.delayed_enter.entry(tick + game_length).or_insert(Vec::new()).push(id);
+
+        let skills = |team: &[UserId]| team.iter().map(|id| (self.user_pool.get_user(id).get_skill(), self.user_pool.get_user(id).real_skill)).collect();
+        let ongoing = OngoingGame {
+            start: tick,
+            length: game_length,
+            end: tick + game_length,
+            start_win_probability: game.win_probability(&self.user_pool),
+            team1_skills: skills(&game.team1),
+            team2_skills: skills(&game.team2),
+            game,
+            imbalance,
+            leavers: Vec::new(),
+        };
+        self.ongoing_games.insert(game_id, ongoing);
+        self.delayed_end.entry(tick + game_length).or_default().push(game_id);
+    }
+
+    /// The user starts playing, either from the start of the game or as a replacement of a leaver
+    fn enter_game(&mut self, game_id: u64, id: UserId, tick: u32, remaining: u32) {
+        // the games played only count the games which ended, a leaver may enter the next one before
+        let mut rng = self.user_rng(id, LEAVE_STREAM, self.user_pool.get_user(&id).games_entered as u64);
+
+        // a game of a single tick cannot be left
+        if remaining > 1 && rng.next_f32() < self.leavers.leave_prob {
+            self.delayed_leave.entry(tick + rng.gen_range(1, remaining)).or_default().push((game_id, id));
         }
+
+        let user = self.user_pool.get_user_mut(&id);
+        user.games_entered += 1;
+        user.session_games += 1;
+        user.state = UserState::Playing;
+        user.failed_check_at = None;
+        user.last_wait = tick - user.get_join_time();
+        user.history.total_wait += user.last_wait;
+        self.window_waits.push(user.last_wait as f32);
+        user.low_priority_games = user.low_priority_games.saturating_sub(1);
+    }
+
+    fn backfill_games(&mut self, tick: u32, events: &mut Vec<Event>) {
+        let policy = match self.backfill {
+            Some(ref policy) => policy,
+            None => return,
+        };
+
+        let mut open = Vec::new();
+        let mut backfilled = Vec::new();
+
+        for request in self.backfill_requests.drain(..) {
+            // the game has ended already
+            let ongoing = match self.ongoing_games.get_mut(&request.game_id) {
+                Some(ongoing) => ongoing,
+                None => continue,
+            };
+
+            // leavers do not come back to the game they left
+            let candidates: Vec<UserId> = self.queue.iter().filter(|id| !ongoing.leavers.contains(id)).cloned().collect();
+
+            let slot = BackfillSlot { skill: request.skill, remaining: ongoing.end - tick, length: ongoing.length };
+            match policy.pick(&candidates, &self.user_pool, &slot) {
+                Some(index) => {
+                    let id = candidates[index];
+                    self.queue.retain(|queued| *queued != id);
+                    ongoing.game.backfill(request.team, id);
+
+                    let user = self.user_pool.get_user(&id);
+                    let skills = if request.team == 1 { &mut ongoing.team1_skills } else { &mut ongoing.team2_skills };
+                    skills.push((user.get_skill(), user.real_skill));
+
+                    events.push(Event::TimedFloat(tick, Cow::Borrowed("backfill_wait"), (tick - request.since) as f32));
+                    backfilled.push((request.game_id, id, slot.remaining));
+                }
+                None => open.push(request),
+            }
+        }
+        self.backfill_requests = open;
+
+        *(self.stats.entry("users_backfilled").or_insert(0.0)) += backfilled.len() as f32;
+        for (game_id, id, remaining) in backfilled {
+            self.enter_game(game_id, id, tick, remaining);
+        }
+    }
+
+    /// The leaver is done with the game right away, the game goes on without the leaver
+    /// The leaver is free to go, the result of the game is recorded when it ends
+    fn on_game_left(&mut self, game_id: u64, id: UserId, tick: u32) {
+        {
+            let ongoing = self.ongoing_games.get_mut(&game_id).unwrap();
+            ongoing.leavers.push(id);
+
+            if self.backfill.is_some() {
+                let team = if ongoing.game.team1.contains(&id) { 1 } else { 2 };
+                let skill = self.user_pool.get_user(&id).get_skill();
+                self.backfill_requests.push(BackfillRequest { game_id, team, skill, since: tick });
+            }
+        }
+        *(self.stats.entry("users_left").or_insert(0.0)) += 1.0;
+
+        let user = self.user_pool.get_user_mut(&id);
+        let rating = user.get_skill();
+        user.update_skill(-self.leavers.rating_penalty);
+        let penalty = user.get_skill() - rating;
+        *(self.stats.entry("rating_from_games").or_insert(0.0)) += penalty;
+        user.low_priority_games = self.leavers.low_priority_games;
+
+        self.on_game_finished(id, tick);
+    }
+
+    fn on_game_ended(&mut self, game_id: u64, tick: u32, events: &mut Vec<Event>) {
+        let ongoing = self.ongoing_games.remove(&game_id).unwrap();
+        let (game, imbalance, leavers) = (&ongoing.game, ongoing.imbalance, &ongoing.leavers);
+
+        let winner = if leavers.is_empty() {
+            self.decider.decide(game, &self.user_pool, &mut self.rng)
+        } else {
+            *(self.stats.entry("games_with_leavers").or_insert(0.0)) += 1.0;
+
+            // a backfilled team is not short-handed
+            let left = |team: &[UserId]| {
+                let replaced = team.iter().filter(|id| game.backfilled.contains(id)).count();
+                team.iter().filter(|id| leavers.contains(id)).count().saturating_sub(replaced)
+            };
+            match self.leavers.forfeit(left(&game.team1), left(&game.team2)) {
+                Some(winner) => winner,
+                None => {
+                    let stayed = |team: &[UserId]| team.iter().filter(|id| !leavers.contains(id)).cloned().collect();
+                    let reduced = Game::new(stayed(&game.team1), stayed(&game.team2));
+                    self.decider.decide(&reduced, &self.user_pool, &mut self.rng)
+                }
+            }
+        };
+
+        let backfilled_before: Vec<f32> = game.backfilled.iter().map(|id| self.user_pool.get_user(id).get_skill()).collect();
+        // the prediction of the ratings right before they are updated
+        let probability = game.win_probability(&self.user_pool);
+        self.window_predictions.add(probability, winner == 1);
+        self.predictions.add(probability, winner == 1);
+
+        // leavers who churned in the meantime took their rating with them, their change is not in the population
+        let rating_sum = |user_pool: &UserPool| game.team1.iter().chain(&game.team2)
+            .map(|id| user_pool.get_user(id))
+            .filter(|user| !user.churned)
+            .map(|user| user.get_skill())
+            .sum::<f32>();
+        let rating_before = rating_sum(&self.user_pool);
+        let deltas = game.process(&mut self.user_pool, winner);
+        *(self.stats.entry("rating_from_games").or_insert(0.0)) += rating_sum(&self.user_pool) - rating_before;
+        if let Some(ref mut log) = self.match_log {
+            log.line(format_args!("{}", match_log_line(&ongoing, probability, winner, &deltas)));
+        }
+
+        if let Some(ref mut log) = self.rating_log {
+            for id in game.team1.iter().chain(&game.team2) {
+                log.line(format_args!("{},{},{}", tick, id, self.user_pool.get_user(id).get_skill()));
+            }
+        }
+
+        for (id, before) in game.backfilled.iter().zip(backfilled_before) {
+            if !leavers.contains(id) {
+                events.push(Event::TimedFloat(tick, Cow::Borrowed("backfilled_rating_delta"), self.user_pool.get_user(id).get_skill() - before));
+            }
+        }
+
+        let winners_count = if winner == 1 { game.team1.len() } else { game.team2.len() };
+        let (winners, losers) = if winner == 1 { (&game.team1, &game.team2) } else { (&game.team2, &game.team1) };
+
+        for (index, &id) in winners.iter().chain(losers).enumerate() {
+            self.on_game_result(id, tick, index < winners_count, imbalance, events);
+
+            // leavers are already gone
+            if !leavers.contains(&id) {
+                self.on_game_finished(id, tick);
+            }
+        }
+    }
+
+    fn on_game_result(&mut self, id: UserId, tick: u32, won: bool, imbalance: f32, events: &mut Vec<Event>) {
+        let mut rng = self.user_rng(id, DRIFT_STREAM, self.user_pool.get_user(&id).get_games_played() as u64);
+
+        let user = self.user_pool.get_user_mut(&id);
+        user.add_game_played();
+        self.skill_dynamics.after_game(user, &mut rng);
+
+        if user.converged_after.is_none() && (user.get_skill() - user.real_skill).abs() < self.convergence_threshold {
+            user.converged_after = Some(user.get_games_played());
+            user.history.converged_at = Some(tick);
+            events.push(Event::Float(Cow::Owned(format!("{}_games_to_converge", user.segment.name())), user.get_games_played() as f32));
+        }
+
+        user.last_imbalance = imbalance;
+        user.history.total_imbalance += imbalance;
+        user.loss_streak = if won { 0 } else { user.loss_streak + 1 };
+
+        if won {
+            user.history.wins += 1;
+        } else {
+            user.history.losses += 1;
+        }
+    }
+
+    /// The user either keeps playing, comes back later or leaves for good
+    fn on_game_finished(&mut self, id: UserId, tick: u32) {
+        let user = self.user_pool.get_user(&id);
+        let games_entered = user.games_entered as u64;
+
+        let keep_playing = if user.session_length > 0 {
+            user.session_games < user.session_length
+        } else {
+            self.user_draw(id, CONTINUE_STREAM, games_entered) < self.retention.continue_prob(user)
+        };
+
+        if keep_playing {
+            self.join_queue(id, tick);
+            return;
+        }
+
+        let user = self.user_pool.get_user_mut(&id);
+        user.state = UserState::Offline;
+        user.offline_since = Some(tick);
+
+        if user.session_length > 0 {
+            user.session_games = 0;
+            *(self.stats.entry("sessions_finished").or_insert(0.0)) += 1.0;
+        }
+
+        if self.user_draw(id, RETURN_STREAM, games_entered) < self.retention.return_prob(self.user_pool.get_user(&id)) {
+            let delay = -self.retention.return_delay_mean * (1.0 - self.user_draw(id, RETURN_DELAY_STREAM, games_entered)).ln();
+            self.delayed_rejoin.entry(tick + (delay as u32).max(1)).or_default().push(id);
+            return;
+        }
+
+        self.churn(id);
+    }
+
+    fn churn(&mut self, id: UserId) {
+        *(self.stats.entry("users_churned").or_insert(0.0)) += 1.0;
+
+        let user = self.user_pool.get_user_mut(&id);
+        user.churned = true;
+        let rating = user.get_skill();
+        *(self.stats.entry("rating_from_departures").or_insert(0.0)) -= rating;
+        *(self.stats.entry("rating_population").or_insert(0.0)) -= 1.0;
+
+        let cohort = self.cohort_of(id);
+        if let Some(cohort) = self.cohorts.get_mut(cohort) {
+            cohort.1 += 1;
+        }
+    }
+
+    /// A churned user came back, so the user is counted as churned only after leaving again
+    fn unchurn(&mut self, id: UserId) {
+        *(self.stats.entry("users_churned").or_insert(0.0)) -= 1.0;
+        self.user_pool.get_user_mut(&id).churned = false;
+        self.on_rating_entered(id);
+
+        let cohort = self.cohort_of(id);
+        if let Some(cohort) = self.cohorts.get_mut(cohort) {
+            cohort.1 -= 1;
+        }
+    }
+
+    fn cohort_of(&self, id: UserId) -> usize {
+        ((self.user_pool.get_user(&id).created_tick.max(1) - 1) / self.cohort_length) as usize
+    }
+
+    fn on_rating_entered(&mut self, id: UserId) {
+        *(self.stats.entry("rating_from_entries").or_insert(0.0)) += self.user_pool.get_user(&id).get_skill();
+        *(self.stats.entry("rating_population").or_insert(0.0)) += 1.0;
+    }
+
+    /// Removes the users who waited longer than their patience, some of them will try again later
+    fn abandon_queue(&mut self, tick: u32) {
+        let impatient = |id: &&UserId| {
+            let user = self.user_pool.get_user(id);
+            (tick - user.get_join_time()) as f32 > user.patience
+        };
+        let (mut abandoned, queue): (Vec<UserId>, Vec<UserId>) = self.queue.iter().partition(impatient);
+        let (low_priority_abandoned, low_priority_queue): (Vec<UserId>, Vec<UserId>) = self.low_priority_queue.iter().partition(impatient);
+        abandoned.extend(low_priority_abandoned);
+        self.queue = queue;
+        self.low_priority_queue = low_priority_queue;
+
+        for &id in &abandoned {
+            let join_time = self.user_pool.get_user(&id).get_join_time();
+            let user = self.user_pool.get_user_mut(&id);
+            user.state = UserState::Offline;
+            user.offline_since = Some(tick);
+
+            if self.user_draw(id, REJOIN_STREAM, join_time as u64) < self.rejoin_prob {
+                self.delayed_rejoin.entry(tick + self.rejoin_delay.max(1)).or_default().push(id);
+            } else {
+                self.churn(id);
+            }
+        }
+
+        let total_abandoned = self.stats.get("users_abandoned").cloned().unwrap_or(0.0) + abandoned.len() as f32;
+        let total_matched = self.stats.get("users_matched").cloned().unwrap_or(0.0);
+        self.stats.insert("users_abandoned", total_abandoned);
+        self.stats.insert("abandon_rate", if total_abandoned > 0.0 { total_abandoned / (total_abandoned + total_matched) } else { 0.0 });
+    }
+
+    fn user_draw(&self, id: UserId, stream: u64, index: u64) -> f32 {
+        self.user_rng(id, stream, index).next_f32()
+    }
+
+    /// Random generator of the user's own decisions, it depends only on the seed, the stream and the index
+    /// so every algorithm simulated with the seed observes the same decisions
+    fn user_rng(&self, id: UserId, stream: u64, index: u64) -> SimRng {
+        new_rng(derive_seed(derive_seed(derive_seed(self.behaviour_seed, stream), id as u64), index))
     }
 
     fn join_queue(&mut self, id: UserId, tick: u32) {
-        self.user_pool.get_user(&id).set_join_time(tick);
-        self.queue.push(id);
+        let user = self.user_pool.get_user_mut(&id);
+        if let Some(offline_since) = user.offline_since.take() {
+            self.skill_dynamics.after_inactivity(user, tick - offline_since);
+        }
+
+        user.set_join_time(tick);
+        user.state = UserState::Queued;
+        user.failed_check_at = None;
+        if user.low_priority_games > 0 {
+            self.low_priority_queue.push(id);
+        } else {
+            self.queue.push(id);
+        }
+    }
+
+    /// Every player of the game has to accept it, otherwise the game is dissolved
+    fn ready_check(&mut self, tick: u32, game: &Game, events: &mut Vec<Event>) -> bool {
+        if self.accept_prob >= 1.0 {
+            return true;
+        }
+
+        let players: Vec<UserId> = game.team1.iter().chain(&game.team2).cloned().collect();
+        let declined: Vec<UserId> = players.iter()
+            .filter(|id| self.user_draw(**id, ACCEPT_STREAM, self.user_pool.get_user(id).ready_checks as u64) >= self.accept_prob)
+            .cloned()
+            .collect();
+
+        for id in &players {
+            self.user_pool.get_user_mut(id).ready_checks += 1;
+        }
+
+        if declined.is_empty() {
+            for id in &players {
+                if let Some(failed_check_at) = self.user_pool.get_user_mut(id).failed_check_at.take() {
+                    events.push(Event::TimedFloat(tick, Cow::Borrowed("ready_check_extra_wait"), (tick - failed_check_at) as f32));
+                }
+            }
+            return true;
+        }
+
+        *(self.stats.entry("ready_checks_failed").or_insert(0.0)) += 1.0;
+        *(self.stats.entry("users_declined").or_insert(0.0)) += declined.len() as f32;
+
+        for id in players {
+            if declined.contains(&id) {
+                let user = self.user_pool.get_user_mut(&id);
+                user.state = UserState::Offline;
+                user.offline_since = Some(tick);
+                self.delayed_rejoin.entry(tick + self.decline_ban.max(1)).or_default().push(id);
+            } else {
+                self.user_pool.get_user_mut(&id).failed_check_at.get_or_insert(tick);
+                self.requeue(id);
+            }
+        }
+
+        false
+    }
+
+    /// Returns a user who accepted a failed ready check to the queue
+    fn requeue(&mut self, id: UserId) {
+        let queue = if self.user_pool.get_user(&id).low_priority_games > 0 { &mut self.low_priority_queue } else { &mut self.queue };
+        requeue(queue, &self.user_pool, id, self.keep_queue_position);
     }
 
     fn get_active_users(&self) -> u32 {
-        let mut sum = self.queue.len() as u32;
+        let mut sum = (self.queue.len() + self.low_priority_queue.len()) as u32;
 
-        for (_, users) in &self.delayed_enter {
-            sum += users.len() as u32;
+        for ongoing in self.ongoing_games.values() {
+            sum += (ongoing.game.team1.len() + ongoing.game.team2.len() - ongoing.leavers.len()) as u32;
         }
 
         sum
     }
 }
 
-enum Event {
-    TimedFloat(u32, &'static str, f32),
-    Float(&'static str, f32),
-    StrParam(&'static str, String)
-}
-
 struct SkillValue {
     avg: f32,
     min: f32,
@@ -356,15 +2008,199 @@ struct SkillValue {
 }
 
 impl SkillValue {
-    fn build(data: &Vec<f32>) -> SkillValue {
+    fn build(data: &[f32]) -> SkillValue {
         let max = data.iter().fold(-1. / 0., |max, v| f32::max(max, *v));
         let min = data.iter().fold(1. / 0., |min, v| f32::min(min, *v));
         let sum: f32 = data.iter().sum();
 
         SkillValue {
-            min: min,
-            max: max,
+            min,
+            max,
             avg: sum / (data.len() as f32),
         }
     }
 }
+
+// ============================ TESTS ============================
+
+#[cfg(test)]
+fn test_model(arguments: &[&str], algorithm: &str, seed: u64) -> Model {
+    let params = app().get_matches_from(["mmmodel"].iter().chain(arguments));
+    build_model(&params, String::from("test"), seed, algorithm, None, None)
+}
+
+#[test]
+fn algorithms_observe_identical_traffic() {
+    let arrivals = |algorithm: &str| {
+        let mut model = test_model(&["-u", "100", "-g", "300"], algorithm, 42);
+        model.run(600, 1, &mut Vec::new());
+
+        model.user_pool.users().iter()
+            .map(|user| (user.created_tick, user.real_skill))
+            .collect::<Vec<(u32, f32)>>()
+    };
+
+    let fifo = arrivals("fifo");
+    assert!(fifo.len() > 100);
+    assert_eq!(fifo, arrivals("skill"));
+    assert_eq!(fifo, arrivals("rnd"));
+}
+
+#[test]
+fn ready_check_dissolves_game() {
+    let mut model = test_model(&["--accept_prob", "0.5", "--decline_ban", "30", "--keep_queue_position"], "fifo", 7);
+
+    let players: Vec<UserId> = (0..10).map(|_| model.user_pool.generate(1500.0, 1500.0)).collect();
+    for (tick, &id) in players.iter().enumerate() {
+        model.join_queue(id, tick as u32 + 1);
+    }
+    let late = model.user_pool.generate(1500.0, 1500.0);
+    model.join_queue(late, 15);
+
+    // the game takes its players out of the queue
+    model.queue.retain(|id| *id == late);
+    let game = Game::new(players[..5].to_vec(), players[5..].to_vec());
+
+    let mut events = Vec::new();
+    assert!(!model.ready_check(20, &game, &mut events));
+
+    let banned = model.delayed_rejoin.remove(&50).unwrap();
+    assert!(!banned.is_empty());
+    for id in &banned {
+        assert_eq!(model.user_pool.get_user(id).state, UserState::Offline);
+    }
+
+    // the players who accepted are back in the order they joined, ahead of the user who joined later
+    let accepted: Vec<UserId> = players.iter().filter(|id| !banned.contains(id)).cloned().collect();
+    assert!(!accepted.is_empty());
+    assert_eq!(model.queue, accepted.iter().cloned().chain(Some(late)).collect::<Vec<UserId>>());
+    for id in &accepted {
+        assert_eq!(model.user_pool.get_user(id).failed_check_at, Some(20));
+    }
+
+    // the next successful ready check reports the wait the failed one added
+    model.accept_prob = 0.999_999;
+    let game = Game::new(accepted.clone(), vec![]);
+    assert!(model.ready_check(35, &game, &mut events));
+
+    let extra_waits: Vec<f32> = events.iter()
+        .filter_map(|event| match *event {
+            Event::TimedFloat(35, ref key, value) if key == "ready_check_extra_wait" => Some(value),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(extra_waits, vec![15.0; accepted.len()]);
+    assert!(model.user_pool.get_user(&accepted[0]).failed_check_at.is_none());
+}
+
+#[test]
+fn rating_total_matches_population() {
+    let mut model = test_model(&["-u", "200", "-g", "400", "--leave_prob", "0.3", "--leaver_penalty", "10", "--continuous_play_prob", "0.8"], "skill", 3);
+    model.run(3000, 1, &mut Vec::new());
+
+    let population: Vec<&UserData> = model.user_pool.users().iter().filter(|user| !user.churned).collect();
+    let rating_total: f32 = population.iter().map(|user| user.get_skill()).sum();
+
+    assert!(model.stats["users_left"] > 0.0);
+    assert!(model.stats["users_churned"] > 0.0);
+    assert_eq!(model.stats["rating_population"], population.len() as f32);
+    assert!((model.stats["rating_total"] - rating_total).abs() < 1.0);
+}
+
+#[test]
+fn leave_rate_matches_leave_prob() {
+    let mut model = test_model(&["-u", "400", "-g", "0", "--leave_prob", "0.2", "--continuous_play_prob", "1", "--max_game_length", "600"], "fifo", 11);
+    model.run(6000, 1, &mut Vec::new());
+
+    // leaves of the games still running at the end are mostly scheduled but not counted yet
+    let entered: u32 = model.user_pool.users().iter().map(|user| user.games_entered).sum();
+    let rate = model.stats["users_left"] / entered as f32;
+    assert!((rate - 0.2).abs() < 0.02, "leave rate {}", rate);
+}
+
+#[test]
+fn returning_trace_users_churn_once() {
+    // 200 users visit 5 times and leave for good after every game
+    let entries = (0..5u32).flat_map(|visit| (0..200u64).map(move |user| TraceEntry {
+        tick: 1 + visit * 600,
+        user,
+        real_skill: 1000.0 + user as f32,
+        skill: None,
+        party: None,
+    })).collect();
+
+    let params = app().get_matches_from(["mmmodel", "--max_game_length", "300"]);
+    let mut model = build_model(&params, String::from("test"), 5, "fifo", Some(TraceArrivals::new("test", entries)), None);
+    model.run(3000, 1, &mut Vec::new());
+
+    let churned = model.user_pool.users().iter().filter(|user| user.churned).count();
+    assert!(churned > 0);
+    assert_eq!(model.stats["users_churned"], churned as f32);
+    assert_eq!(model.cohorts[0], (200, churned as u32));
+}
+
+#[test]
+fn replications_aggregate_as_they_go() {
+    let replications = Mutex::new(Replications::default());
+
+    for (index, values) in [[1.0, 3.0], [4.0, 6.0]].iter().enumerate() {
+        let mut run = ReplicationRun::new(index == 0, &replications);
+        run.write(Event::StrParam("algorithm", String::from("fifo")));
+        for value in values {
+            run.write(Event::TimedFloat(1, Cow::Borrowed("delta"), *value));
+            run.write(Event::Float(Cow::Borrowed("total"), *value));
+        }
+        run.write(Event::TimedFloat(2, Cow::Borrowed("delta"), values[0]));
+        run.finish();
+    }
+
+    let events = replications.into_inner().unwrap().events("test", 1, 2);
+    let params: Vec<&str> = events.iter().filter_map(|event| match *event { Event::StrParam(key, _) => Some(key), _ => None }).collect();
+    assert_eq!(params, vec!["name", "seed", "replications", "algorithm"]);
+
+    // the values of a tick are averaged per run first: 2 and 5
+    let bands: Vec<(u32, f32)> = events.iter().filter_map(|event| match *event { Event::TimedBand(tick, _, mean, _, _) => Some((tick, mean)), _ => None }).collect();
+    assert_eq!(bands, vec![(1, 3.5), (2, 2.5)]);
+    assert!(events.iter().any(|event| match *event { Event::FloatBand(ref key, mean, _, _) => key == "total" && mean == 3.5, _ => false }));
+}
+
+#[test]
+fn summaries_keep_single_values() {
+    let mut sink = Summarizing::new(Vec::new());
+    sink.write(Event::TimedFloat(1, Cow::Borrowed("accuracy"), 0.25));
+    sink.write(Event::TimedFloat(2, Cow::Borrowed("accuracy"), 0.75));
+    sink.write(Event::Float(Cow::Borrowed("accuracy"), 0.5));
+    sink.write(Event::Float(Cow::Borrowed("smurf_games_to_converge"), 10.0));
+    sink.write(Event::Float(Cow::Borrowed("smurf_games_to_converge"), 20.0));
+    sink.write(Event::FloatBand(Cow::Borrowed("total_wait_p95"), 30.0, 25.0, 35.0));
+
+    let summary: Vec<(String, f32, Option<f32>)> = sink.summary().into_iter().map(|v| (v.key.into_owned(), v.avg, v.last)).collect();
+    assert_eq!(summary, vec![
+        (String::from("accuracy"), 0.5, Some(0.75)),
+        (String::from("accuracy"), 0.5, None),
+        (String::from("smurf_games_to_converge"), 15.0, None),
+        (String::from("total_wait_p95"), 30.0, None),
+    ]);
+}
+
+#[test]
+fn argument_names_are_unique() {
+    let mut names: Vec<&str> = ARGUMENTS.iter().map(|&(name, _)| name).collect();
+    names.sort();
+    names.dedup();
+
+    assert_eq!(names.len(), ARGUMENTS.len());
+}
+
+#[test]
+fn command_line_arguments_with_defaults() {
+    let params = app().get_matches_from(vec!["mmmodel", "--backfill", "--burst", "1:2:3", "--burst", "5:5:2"]);
+    let arguments = command_line_arguments(&params);
+    let value = |name: &str| arguments.iter().find(|argument| argument.0 == name).map(|argument| argument.1.as_str());
+
+    assert_eq!(value("backfill"), Some("true"));
+    assert_eq!(value("burst"), Some("1:2:3 5:5:2"));
+    assert_eq!(value("team_size"), Some("5"));
+    assert_eq!(value("match_log"), None);
+    assert_eq!(value("seed"), None);
+}
@@ -0,0 +1,92 @@
+use data::data_lines;
+
+/// A game of a match log, players are identified by their external ids
+#[derive(Debug, PartialEq)]
+pub struct MatchRecord {
+    pub tick: u32,
+    pub team1: Vec<u64>,
+    pub team2: Vec<u64>,
+    pub winner: u32,
+    /// Ratings of the players in the log, used as the starting rating of a player seen for the first time
+    pub team1_ratings: Option<Vec<f32>>,
+    pub team2_ratings: Option<Vec<f32>>,
+    /// Real skill levels are known only for the logs of simulations
+    pub team1_real_skills: Option<Vec<f32>>,
+    pub team2_real_skills: Option<Vec<f32>>,
+}
+
+pub fn load_match_log(path: &str) -> Vec<MatchRecord> {
+    let content = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Unable to read match log {}: {}", path, e));
+
+    parse_match_log(&content)
+}
+
+/// Parses a match log with a header line, the columns are found by their names: `team1`, `team2` and `winner` are
+/// required, `tick`, `team<n>_ratings` and `team<n>_real_skills` are optional. Lists of players are space separated.
+pub fn parse_match_log(content: &str) -> Vec<MatchRecord> {
+    let header = data_lines(content, 0, "match log").next().expect("Empty match log");
+    let column = |name: &str| header.iter().position(|v| *v == name);
+    let required = |name: &str| column(name).unwrap_or_else(|| panic!("Match log without the {} column", name));
+
+    let (team1, team2, winner) = (required("team1"), required("team2"), required("winner"));
+    let tick = column("tick");
+    let ratings = (column("team1_ratings"), column("team2_ratings"));
+    let real_skills = (column("team1_real_skills"), column("team2_real_skills"));
+
+    let mut records: Vec<MatchRecord> = data_lines(content, header.len(), "match log").skip(1)
+        .map(|values| {
+            let ids = |index: usize| values[index].split_whitespace().map(|v| v.parse().unwrap()).collect();
+            let skills = |index: Option<usize>| index.map(|index| values[index].split_whitespace().map(|v| v.parse().unwrap()).collect());
+
+            MatchRecord {
+                tick: tick.map(|index| values[index].parse().unwrap()).unwrap_or(0),
+                team1: ids(team1),
+                team2: ids(team2),
+                winner: values[winner].parse().unwrap(),
+                team1_ratings: skills(ratings.0),
+                team2_ratings: skills(ratings.1),
+                team1_real_skills: skills(real_skills.0),
+                team2_real_skills: skills(real_skills.1),
+            }
+        })
+        .collect();
+
+    // the games are replayed in the order they started
+    records.sort_by_key(|record| record.tick);
+    records
+}
+
+// ============================ TESTS ============================
+
+#[test]
+fn match_log_parse() {
+    let records = parse_match_log("tick,team1,team2,winner,team1_ratings,team2_ratings\n\
+                                   7,1 2,3 4,2,1500 1510,1490 1480\n\
+                                   # comment\n\
+                                   3,5,6,1,1600,1400\n");
+
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].tick, 3);
+    assert_eq!(records[1].team1, vec![1, 2]);
+    assert_eq!(records[1].team2, vec![3, 4]);
+    assert_eq!(records[1].winner, 2);
+    assert_eq!(records[1].team2_ratings, Some(vec![1490.0, 1480.0]));
+    assert_eq!(records[1].team1_real_skills, None);
+}
+
+#[test]
+fn match_log_minimal() {
+    let records = parse_match_log("winner,team2,team1\n1,9,8\n");
+
+    assert_eq!(records[0], MatchRecord {
+        tick: 0,
+        team1: vec![8],
+        team2: vec![9],
+        winner: 1,
+        team1_ratings: None,
+        team2_ratings: None,
+        team1_real_skills: None,
+        team2_real_skills: None,
+    });
+}
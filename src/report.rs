@@ -0,0 +1,174 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// Version of the report layout, bumped whenever the rows change their meaning
+pub const REPORT_FORMAT: u32 = 4;
+
+/// Columns of every kind of row following the kind itself, written into the header of the report
+const SCHEMA: &[(&str, &str)] = &[
+    ("arg", "name,value"),
+    ("param", "name,value"),
+    ("float", "name,value"),
+    ("float_band", "name,mean,low,high"),
+    ("timed", "tick,name,value"),
+    ("band", "tick,name,mean,low,high"),
+];
+
+const REPORT_DIR: &str = "reports";
+
+/// Name of a timed stat, owned only when it is built at runtime, e.g. per cohort
+pub type StatKey = Cow<'static, str>;
+
+pub enum Event {
+    TimedFloat(u32, StatKey, f32),
+    /// Mean with the lower and upper bound of the confidence band
+    TimedBand(u32, StatKey, f32, f32, f32),
+    Float(StatKey, f32),
+    /// Mean with the confidence band of a single value across replications
+    FloatBand(StatKey, f32, f32, f32),
+    StrParam(&'static str, String)
+}
+
+/// Receives the events of a run as they happen
+pub trait EventSink: Send {
+    fn write(&mut self, event: Event);
+
+    /// Called once after the last event
+    fn finish(&mut self) {}
+}
+
+/// Keeps all the events in memory, e.g. to inspect a run in the tests
+impl EventSink for Vec<Event> {
+    fn write(&mut self, event: Event) {
+        self.push(event);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReportOptions {
+    pub compress: bool,
+}
+
+enum Output {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+/// Lines of a file in `reports`, written through a buffer and optionally gzip compressed
+pub struct CsvFile {
+    path: String,
+    output: Option<Output>,
+}
+
+impl CsvFile {
+    /// Creates `reports/<name>.csv`, or `reports/<name>.csv.gz` when compressed
+    pub fn create(name: &str, compress: bool) -> CsvFile {
+        std::fs::create_dir(REPORT_DIR).ok();
+
+        let extension = if compress { "csv.gz" } else { "csv" };
+        let path = format!("{}/{}.{}", REPORT_DIR, name, extension);
+        let file = BufWriter::new(File::create(&path).unwrap_or_else(|e| panic!("Unable to create {}: {}", path, e)));
+
+        let output = if compress {
+            Output::Gzip(GzEncoder::new(file, Compression::default()))
+        } else {
+            Output::Plain(file)
+        };
+
+        CsvFile { path, output: Some(output) }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn line(&mut self, args: std::fmt::Arguments) {
+        let result = match self.output {
+            Some(Output::Plain(ref mut out)) => writeln!(out, "{}", args),
+            Some(Output::Gzip(ref mut out)) => writeln!(out, "{}", args),
+            None => panic!("{} is already finished", self.path),
+        };
+        result.unwrap_or_else(|e| panic!("Unable to write {}: {}", self.path, e));
+    }
+
+    /// Flushes the rest of the file, returns false when it was finished before
+    pub fn finish(&mut self) -> bool {
+        let result = match self.output.take() {
+            Some(Output::Plain(mut out)) => out.flush(),
+            Some(Output::Gzip(out)) => out.finish().and_then(|mut out| out.flush()),
+            None => return false,
+        };
+        result.unwrap_or_else(|e| panic!("Unable to write {}: {}", self.path, e));
+        true
+    }
+}
+
+/// Writes the events into `reports/<name>.csv[.gz]` through a buffer while the run goes on.
+///
+/// Every row starts with its kind: `report,<format>,<crate version>` and `schema,<kind>,<columns>...` header,
+/// `arg,<name>,<value>` command line
+/// arguments, `param,<name>,<value>` parameters of the run, `float,<name>,<value>` single values,
+/// `timed,<tick>,<name>,<value>` values over time, `band,<tick>,<name>,<mean>,<low>,<high>` and
+/// `float_band,<name>,<mean>,<low>,<high>` aggregated replications
+pub struct ReportWriter {
+    file: CsvFile,
+}
+
+impl ReportWriter {
+    pub fn create(name: &str, arguments: &[(String, String)], options: &ReportOptions) -> ReportWriter {
+        let mut writer = ReportWriter { file: CsvFile::create(name, options.compress) };
+        writer.line(format_args!("report,{},{}", REPORT_FORMAT, env!("CARGO_PKG_VERSION")));
+        for (kind, columns) in SCHEMA {
+            writer.line(format_args!("schema,{},{}", kind, columns));
+        }
+        for (key, value) in arguments {
+            writer.line(format_args!("arg,{},{}", key, csv_field(value)));
+        }
+
+        writer
+    }
+
+    fn line(&mut self, args: std::fmt::Arguments) {
+        self.file.line(args);
+    }
+}
+
+impl EventSink for ReportWriter {
+    fn write(&mut self, event: Event) {
+        match event {
+            Event::StrParam(name, value) => self.line(format_args!("param,{},{}", name, csv_field(&value))),
+            Event::Float(name, value) => self.line(format_args!("float,{},{}", name, value)),
+            Event::FloatBand(name, mean, low, high) => self.line(format_args!("float_band,{},{},{},{}", name, mean, low, high)),
+            Event::TimedFloat(tick, name, value) => self.line(format_args!("timed,{},{},{}", tick, name, value)),
+            Event::TimedBand(tick, name, mean, low, high) => self.line(format_args!("band,{},{},{},{},{}", tick, name, mean, low, high)),
+        }
+    }
+
+    fn finish(&mut self) {
+        if self.file.finish() {
+            println!("Report saved into: {}", self.file.path());
+        }
+    }
+}
+
+/// Quotes the values which contain separators, e.g. the `Debug` output of the algorithm
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+// ============================ TESTS ============================
+
+#[test]
+fn csv_field_quotes() {
+    assert_eq!(csv_field("1500"), "1500");
+    assert_eq!(csv_field("Alg { size: 5, factor: 1.0 }"), "\"Alg { size: 5, factor: 1.0 }\"");
+    assert_eq!(csv_field("a \"b\""), "\"a \"\"b\"\"\"");
+}
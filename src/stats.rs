@@ -0,0 +1,394 @@
+#[derive(Debug)]
+pub struct Summary {
+    pub count: usize,
+    pub mean: f32,
+    pub std_dev: f32,
+}
+
+impl Summary {
+    pub fn build(data: &[f32]) -> Summary {
+        let count = data.len();
+        if count == 0 {
+            return Summary { count, mean: 0.0, std_dev: 0.0 };
+        }
+
+        let mean = data.iter().sum::<f32>() / count as f32;
+
+        let std_dev = if count < 2 {
+            0.0
+        } else {
+            let squares: f32 = data.iter().map(|v| (v - mean) * (v - mean)).sum();
+            (squares / (count - 1) as f32).sqrt()
+        };
+
+        Summary { count, mean, std_dev }
+    }
+
+    /// Half width of the 95% confidence interval of the mean (Student's t)
+    pub fn confidence_95(&self) -> f32 {
+        if self.count < 2 {
+            return 0.0;
+        }
+
+        t_quantile_975(self.count - 1) * self.std_dev / (self.count as f32).sqrt()
+    }
+}
+
+/// Summary of values added one by one without keeping them (Welford)
+#[derive(Debug, Default, Clone)]
+pub struct RunningSummary {
+    count: usize,
+    mean: f32,
+    /// Sum of the squared differences from the mean
+    squares: f32,
+}
+
+impl RunningSummary {
+    pub fn add(&mut self, value: f32) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f32;
+        self.squares += delta * (value - self.mean);
+    }
+
+    pub fn summary(&self) -> Summary {
+        let std_dev = if self.count < 2 { 0.0 } else { (self.squares / (self.count - 1) as f32).sqrt() };
+        Summary { count: self.count, mean: self.mean, std_dev }
+    }
+}
+
+fn t_quantile_975(degrees: usize) -> f32 {
+    static TABLE: [f32; 30] = [
+        12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228,
+        2.201, 2.179, 2.160, 2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086,
+        2.080, 2.074, 2.069, 2.064, 2.060, 2.056, 2.052, 2.048, 2.045, 2.042,
+    ];
+
+    match degrees {
+        0 => f32::INFINITY,
+        1..=30 => TABLE[degrees - 1],
+        31..=60 => 2.000,
+        61..=120 => 1.980,
+        _ => 1.960,
+    }
+}
+
+/// Nearest-rank percentile of sorted values, `p` is in (0, 100]
+pub fn percentile(sorted: &[f32], p: f32) -> f32 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+
+    let rank = (p / 100.0 * sorted.len() as f32).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Counts of whole values, e.g. waits in ticks, its size follows the largest value instead of the amount of values
+#[derive(Debug, Default)]
+pub struct Histogram {
+    counts: Vec<u32>,
+    total: u32,
+}
+
+impl Histogram {
+    pub fn add(&mut self, value: u32) {
+        let value = value as usize;
+        if self.counts.len() <= value {
+            self.counts.resize(value + 1, 0);
+        }
+        self.counts[value] += 1;
+        self.total += 1;
+    }
+
+    /// Nearest-rank percentile of the values, `p` is in (0, 100]
+    pub fn percentile(&self, p: f32) -> f32 {
+        if self.total == 0 {
+            return 0.0;
+        }
+
+        let rank = ((p / 100.0 * self.total as f32).ceil() as u32).clamp(1, self.total);
+        let mut seen = 0;
+        for (value, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return value as f32;
+            }
+        }
+        unreachable!()
+    }
+
+    /// Counts of the values falling into the buckets of the given width, starting at zero
+    pub fn buckets(&self, width: f32) -> Vec<u32> {
+        let mut buckets = Vec::new();
+
+        for (value, &count) in self.counts.iter().enumerate().filter(|&(_, &count)| count > 0) {
+            let bucket = (value as f32 / width) as usize;
+            if buckets.len() <= bucket {
+                buckets.resize(bucket + 1, 0);
+            }
+            buckets[bucket] += count;
+        }
+
+        buckets
+    }
+}
+
+/// Quality of the predicted win probabilities against the results
+#[derive(Debug, Default)]
+pub struct PredictionStats {
+    pub count: u32,
+    correct: f32,
+    log_loss: f32,
+    brier: f32,
+    /// Amount of predictions, sum of the predictions and amount of wins per tenth of the probability
+    calibration: [(u32, f32, u32); 10],
+}
+
+impl PredictionStats {
+    pub fn add(&mut self, probability: f32, won: bool) {
+        let result = if won { 1.0 } else { 0.0 };
+
+        self.count += 1;
+        // a coin flip prediction is half right
+        self.correct += if probability == 0.5 { 0.5 } else if (probability > 0.5) == won { 1.0 } else { 0.0 };
+
+        let clamped = probability.clamp(1e-6, 1.0 - 1e-6);
+        self.log_loss -= if won { clamped.ln() } else { (1.0 - clamped).ln() };
+        self.brier += (probability - result) * (probability - result);
+
+        let bucket = &mut self.calibration[((probability * 10.0) as usize).min(9)];
+        bucket.0 += 1;
+        bucket.1 += probability;
+        bucket.2 += won as u32;
+    }
+
+    pub fn accuracy(&self) -> f32 {
+        self.mean(self.correct)
+    }
+
+    pub fn log_loss(&self) -> f32 {
+        self.mean(self.log_loss)
+    }
+
+    pub fn brier(&self) -> f32 {
+        self.mean(self.brier)
+    }
+
+    /// Lower bound of the bucket, mean prediction, observed win rate and amount of predictions of the non-empty buckets
+    pub fn calibration(&self) -> Vec<(f32, f32, f32, u32)> {
+        self.calibration.iter().enumerate()
+            .filter(|&(_, bucket)| bucket.0 > 0)
+            .map(|(index, &(count, predicted, wins))| (index as f32 / 10.0, predicted / count as f32, wins as f32 / count as f32, count))
+            .collect()
+    }
+
+    fn mean(&self, sum: f32) -> f32 {
+        if self.count == 0 { 0.0 } else { sum / self.count as f32 }
+    }
+}
+
+/// Ranks starting at 1, tied values get the average of their ranks
+pub fn ranks(values: &[f32]) -> Vec<f32> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|a, b| values[*a].partial_cmp(&values[*b]).unwrap());
+
+    let mut ranks = vec![0.0; values.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && values[order[end]] == values[order[start]] {
+            end += 1;
+        }
+
+        let rank = (start + end + 1) as f32 / 2.0;
+        for index in &order[start..end] {
+            ranks[*index] = rank;
+        }
+        start = end;
+    }
+
+    ranks
+}
+
+pub fn pearson(x: &[f32], y: &[f32]) -> f32 {
+    let mean_x = Summary::build(x).mean;
+    let mean_y = Summary::build(y).mean;
+
+    let (mut covariance, mut variance_x, mut variance_y) = (0.0, 0.0, 0.0);
+    for (a, b) in x.iter().zip(y) {
+        covariance += (a - mean_x) * (b - mean_y);
+        variance_x += (a - mean_x) * (a - mean_x);
+        variance_y += (b - mean_y) * (b - mean_y);
+    }
+
+    if variance_x == 0.0 || variance_y == 0.0 {
+        return 0.0;
+    }
+
+    covariance / (variance_x * variance_y).sqrt()
+}
+
+/// Spearman's rank correlation
+pub fn spearman(x: &[f32], y: &[f32]) -> f32 {
+    pearson(&ranks(x), &ranks(y))
+}
+
+/// Kendall's tau-b, which accounts for ties
+pub fn kendall(x: &[f32], y: &[f32]) -> f32 {
+    let (mut concordant, mut discordant, mut ties_x, mut ties_y) = (0i64, 0i64, 0i64, 0i64);
+
+    for i in 0..x.len() {
+        for j in (i + 1)..x.len() {
+            let dx = x[i] - x[j];
+            let dy = y[i] - y[j];
+
+            if dx == 0.0 && dy == 0.0 {
+                continue;
+            } else if dx == 0.0 {
+                ties_x += 1;
+            } else if dy == 0.0 {
+                ties_y += 1;
+            } else if (dx > 0.0) == (dy > 0.0) {
+                concordant += 1;
+            } else {
+                discordant += 1;
+            }
+        }
+    }
+
+    let denominator = (((concordant + discordant + ties_x) * (concordant + discordant + ties_y)) as f64).sqrt();
+    if denominator == 0.0 { 0.0 } else { ((concordant - discordant) as f64 / denominator) as f32 }
+}
+
+/// Share of the top `n` by the first values who are in the top `n` by the second values as well
+pub fn top_n_precision(x: &[f32], y: &[f32], n: usize) -> f32 {
+    let n = n.min(x.len());
+    if n == 0 {
+        return 0.0;
+    }
+
+    let top = |values: &[f32]| {
+        let mut order: Vec<usize> = (0..values.len()).collect();
+        order.sort_by(|a, b| values[*b].partial_cmp(&values[*a]).unwrap());
+        order.truncate(n);
+        order
+    };
+
+    let top_y = top(y);
+    top(x).iter().filter(|index| top_y.contains(index)).count() as f32 / n as f32
+}
+
+/// Splits the values into `count` tiers of about the same size by their percentile, tier 0 holds the lowest values
+pub fn tiers(values: &[f32], count: usize) -> Vec<usize> {
+    ranks(values).iter()
+        .map(|rank| (((rank - 1.0) * count as f32 / values.len() as f32) as usize).min(count - 1))
+        .collect()
+}
+
+// ============================ TESTS ============================
+
+#[test]
+fn summary_single_value() {
+    let summary = Summary::build(&[42.0]);
+
+    assert_eq!(summary.mean, 42.0);
+    assert_eq!(summary.confidence_95(), 0.0);
+}
+
+#[test]
+fn summary_confidence() {
+    let summary = Summary::build(&[1.0, 2.0, 3.0, 4.0, 5.0]);
+
+    assert!((summary.mean - 3.0).abs() < 0.001);
+    assert!((summary.std_dev - 1.5811).abs() < 0.001);
+    // 2.776 * 1.5811 / sqrt(5)
+    assert!((summary.confidence_95() - 1.9630).abs() < 0.001);
+}
+
+#[test]
+fn running_summary() {
+    let mut running = RunningSummary::default();
+    for value in &[1.0, 2.0, 3.0, 4.0, 5.0] {
+        running.add(*value);
+    }
+
+    let summary = running.summary();
+    assert_eq!(summary.count, 5);
+    assert!((summary.mean - 3.0).abs() < 0.001);
+    assert!((summary.std_dev - 1.5811).abs() < 0.001);
+    assert_eq!(RunningSummary::default().summary().std_dev, 0.0);
+}
+
+#[test]
+fn percentiles() {
+    let values: Vec<f32> = (1..101).map(|v| v as f32).collect();
+
+    assert_eq!(percentile(&values, 50.0), 50.0);
+    assert_eq!(percentile(&values, 95.0), 95.0);
+    assert_eq!(percentile(&values, 100.0), 100.0);
+    assert_eq!(percentile(&[7.0], 99.0), 7.0);
+    assert_eq!(percentile(&[], 50.0), 0.0);
+}
+
+#[test]
+fn histogram_percentiles() {
+    let mut histogram = Histogram::default();
+    for value in (1..101).rev() {
+        histogram.add(value);
+    }
+
+    assert_eq!(histogram.percentile(50.0), 50.0);
+    assert_eq!(histogram.percentile(95.0), 95.0);
+    assert_eq!(histogram.percentile(100.0), 100.0);
+    assert_eq!(Histogram::default().percentile(50.0), 0.0);
+
+    let mut histogram = Histogram::default();
+    for &value in &[0, 9, 10, 35, 0] {
+        histogram.add(value);
+    }
+    assert_eq!(histogram.percentile(40.0), 0.0);
+    assert_eq!(histogram.percentile(99.0), 35.0);
+    assert_eq!(histogram.buckets(10.0), vec![3, 1, 0, 1]);
+}
+
+#[test]
+fn prediction_stats() {
+    let mut stats = PredictionStats::default();
+    stats.add(0.8, true);
+    stats.add(0.8, false);
+    stats.add(0.3, false);
+    stats.add(0.5, true);
+
+    assert!((stats.accuracy() - 0.625).abs() < 0.0001);
+    // (0.04 + 0.64 + 0.09 + 0.25) / 4
+    assert!((stats.brier() - 0.255).abs() < 0.0001);
+    // -(ln 0.8 + ln 0.2 + ln 0.7 + ln 0.5) / 4
+    assert!((stats.log_loss() - 0.7206).abs() < 0.001);
+
+    let calibration = stats.calibration();
+    assert_eq!(calibration.len(), 3);
+    assert_eq!(calibration[2], (0.8, 0.8, 0.5, 2));
+}
+
+#[test]
+fn rank_correlations() {
+    let x = [1.0, 2.0, 3.0, 4.0, 5.0];
+
+    assert!((spearman(&x, &[10.0, 20.0, 30.0, 40.0, 500.0]) - 1.0).abs() < 0.0001);
+    assert!((spearman(&x, &[5.0, 4.0, 3.0, 2.0, 1.0]) + 1.0).abs() < 0.0001);
+    assert!((kendall(&x, &[10.0, 20.0, 30.0, 40.0, 500.0]) - 1.0).abs() < 0.0001);
+    // one swapped pair out of ten
+    assert!((kendall(&x, &[1.0, 3.0, 2.0, 4.0, 5.0]) - 0.8).abs() < 0.0001);
+
+    assert_eq!(ranks(&[3.0, 1.0, 3.0, 2.0]), vec![3.5, 1.0, 3.5, 2.0]);
+
+    assert_eq!(top_n_precision(&x, &[1.0, 2.0, 3.0, 5.0, 4.0], 2), 1.0);
+    assert_eq!(top_n_precision(&x, &[1.0, 2.0, 5.0, 4.0, 3.0], 2), 0.5);
+}
+
+#[test]
+fn percentile_tiers() {
+    assert_eq!(tiers(&[40.0, 10.0, 30.0, 20.0], 2), vec![1, 0, 1, 0]);
+    assert_eq!(tiers(&[5.0, 1.0, 3.0, 2.0, 4.0], 5), vec![4, 0, 2, 1, 3]);
+    assert_eq!(tiers(&[1.0, 2.0, 3.0], 1), vec![0, 0, 0]);
+}